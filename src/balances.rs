@@ -0,0 +1,166 @@
+//! Totals of the amounts locked in the transfers of a tree, kept up to date
+//! as leaves come and go so that reading them does not touch every leaf.
+//!
+//! The first amount of a transfer's balance is locked by its initiator and the
+//! second by its responder. Assets with nothing locked have no entry.
+
+use super::{format::Fields, Address, Tree, U256};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Locked {
+    by_asset: BTreeMap<Address, U256>,
+    /// Keyed by participant then asset, so that one participant's balances
+    /// are a range.
+    by_participant: BTreeMap<(Address, Address), U256>,
+}
+
+impl Locked {
+    pub fn add(&mut self, state: &Fields) {
+        self.apply(state, U256::wrapping_add);
+    }
+
+    pub fn remove(&mut self, state: &Fields) {
+        self.apply(state, U256::wrapping_sub);
+    }
+
+    /// Updates the totals with the amounts of a leaf. The arithmetic wraps, so
+    /// that removing a leaf always undoes adding it, though no real set of
+    /// transfers could lock more than 2^256 of an asset.
+    fn apply(&mut self, state: &Fields, op: fn(U256, U256) -> U256) {
+        let asset_id = state.asset_id();
+        let amount = state.amount();
+        update(
+            &mut self.by_asset,
+            asset_id,
+            amount[0].wrapping_add(amount[1]),
+            op,
+        );
+        update(
+            &mut self.by_participant,
+            (state.initiator(), asset_id),
+            amount[0],
+            op,
+        );
+        update(
+            &mut self.by_participant,
+            (state.responder(), asset_id),
+            amount[1],
+            op,
+        );
+    }
+}
+
+fn update<K: Ord>(
+    totals: &mut BTreeMap<K, U256>,
+    key: K,
+    amount: U256,
+    op: fn(U256, U256) -> U256,
+) {
+    if amount == U256::ZERO {
+        return;
+    }
+    let total = op(totals.get(&key).copied().unwrap_or_default(), amount);
+    if total == U256::ZERO {
+        totals.remove(&key);
+    } else {
+        totals.insert(key, total);
+    }
+}
+
+impl<H> Tree<H> {
+    /// The total amount locked in the transfers of the tree, for each asset.
+    pub fn locked_balances(&self) -> BTreeMap<Address, U256> {
+        self.leaf_data.locked.by_asset.clone()
+    }
+
+    /// The amount the participant has locked in transfers of the tree as
+    /// initiator or responder, for each asset.
+    pub fn locked_balances_of(&self, participant: Address) -> BTreeMap<Address, U256> {
+        self.leaf_data
+            .locked
+            .by_participant
+            .range((participant, Address::default())..=(participant, [0xff; 20]))
+            .map(|(&(_, asset_id), &amount)| (asset_id, amount))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{transfer_between, ALICE, BOB};
+    use crate::CoreTransferState;
+
+    /// Sums the balances the slow way, from every leaf.
+    fn recount(tree: &Tree, states: &[CoreTransferState]) -> Locked {
+        let mut locked = Locked::default();
+        for state in states {
+            if tree.contains(state.transfer_id) {
+                locked.add(&Fields::read(&state.encode()));
+            }
+        }
+        locked
+    }
+
+    fn check(tree: &Tree, states: &[CoreTransferState]) {
+        let expected = recount(tree, states);
+        assert_eq!(tree.leaf_data.locked.by_asset, expected.by_asset);
+        assert_eq!(
+            tree.leaf_data.locked.by_participant,
+            expected.by_participant
+        );
+    }
+
+    #[test]
+    fn totals_follow_every_change() {
+        let mut states: Vec<_> = (0..40).map(transfer_between).collect();
+        let mut tree = Tree::new();
+        for state in &states[..10] {
+            tree.insert_state(state).unwrap();
+        }
+        check(&tree, &states);
+
+        let encoded: Vec<_> = states[10..30].iter().map(|s| s.encode()).collect();
+        tree.insert_many_bytes(&encoded).unwrap();
+        check(&tree, &states);
+
+        let checkpoint = tree.checkpoint();
+        states[3].balance.amount[0] += U256::from(5u32);
+        tree.upsert_state(&states[3]).unwrap();
+        check(&tree, &states);
+        tree.delete_id(states[4].transfer_id);
+        tree.delete_many(states[20..25].iter().map(|s| s.transfer_id));
+        check(&tree, &states);
+
+        tree.rollback(checkpoint).unwrap();
+        states[3].balance.amount[0] -= U256::from(5u32);
+        check(&tree, &states);
+
+        // Deleting everything leaves no entries behind
+        tree.delete_many(states.iter().map(|s| s.transfer_id));
+        assert!(tree.locked_balances().is_empty());
+        assert!(tree.leaf_data.locked.by_participant.is_empty());
+    }
+
+    #[test]
+    fn split_by_participant() {
+        let mut tree = Tree::new();
+        let mut state = transfer_between(0);
+        state.balance.amount = [U256::from(7u32), U256::from(2u32)];
+        tree.insert_state(&state).unwrap();
+
+        let mut other = transfer_between(1);
+        other.asset_id = state.asset_id;
+        other.balance.amount = [U256::from(5u32), U256::ZERO];
+        tree.insert_state(&other).unwrap();
+
+        let asset = state.asset_id;
+        let single = |amount: u32| vec![(asset, U256::from(amount))].into_iter().collect();
+        assert_eq!(tree.locked_balances(), single(14));
+        // Alice initiated the first and Bob the second
+        assert_eq!(tree.locked_balances_of(ALICE), single(7));
+        assert_eq!(tree.locked_balances_of(BOB), single(7));
+        assert!(tree.locked_balances_of([0xc; 20]).is_empty());
+    }
+}
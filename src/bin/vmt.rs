@@ -0,0 +1,196 @@
+//! Computes roots and proofs of a set of transfers from the command line, for
+//! checking a dispute offline.
+//!
+//! Transfer states are read one per line as 0x-hex ABI encoded
+//! `CoreTransferState`s. Blank lines are skipped.
+
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::process;
+
+use vector_merkle_tree::{hex_to_bytes32, verify, Proof, Tree};
+
+const USAGE: &str = "\
+Usage:
+    vmt root [FILE]
+    vmt leaves [FILE]
+    vmt proof TRANSFER_ID [FILE]
+    vmt verify ROOT LEAF_HASH [SIBLING...]
+
+Transfer states are read from FILE, or from stdin if it is missing or -.
+`leaves` prints the transfer id and leaf hash of each transfer in tree order.
+`proof` prints the sibling hashes of the transfer, from the leaf upwards.
+`verify` exits with status 1 if the proof does not hold.";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    match run(&args, stdin.lock(), &mut stdout.lock()) {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(message) => {
+            eprintln!("{}", message);
+            process::exit(2);
+        }
+    }
+}
+
+/// Runs a command, returning whether it succeeded. Anything that stops it
+/// from running at all is an error.
+fn run(args: &[String], stdin: impl BufRead, out: &mut impl Write) -> Result<bool, String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["root", rest @ ..] => {
+            let tree = read_tree(input(rest, stdin)?)?;
+            writeln!(out, "{}", prefixed(tree.root())).map_err(|e| e.to_string())?;
+        }
+        ["leaves", rest @ ..] => {
+            let tree = read_tree(input(rest, stdin)?)?;
+            for (transfer_id, hash) in tree.iter() {
+                writeln!(out, "{} {}", prefixed(transfer_id), prefixed(hash))
+                    .map_err(|e| e.to_string())?;
+            }
+        }
+        ["proof", transfer_id, rest @ ..] => {
+            let transfer_id = parse(transfer_id, "transfer id")?;
+            let tree = read_tree(input(rest, stdin)?)?;
+            let proof = tree
+                .proof(transfer_id)
+                .ok_or_else(|| format!("No transfer {} in the tree", prefixed(transfer_id)))?;
+            for sibling in proof.siblings {
+                writeln!(out, "{}", prefixed(sibling)).map_err(|e| e.to_string())?;
+            }
+        }
+        ["verify", root, leaf_hash, siblings @ ..] => {
+            let root = parse(root, "root")?;
+            let leaf_hash = parse(leaf_hash, "leaf hash")?;
+            let siblings = siblings
+                .iter()
+                .map(|sibling| parse(sibling, "sibling"))
+                .collect::<Result<Vec<_>, _>>()?;
+            // The tool only builds Keccak256 trees, whose proofs need no
+            // directions since that hasher sorts each pair
+            let proof = Proof {
+                siblings,
+                directions: Vec::new(),
+            };
+            let valid = verify(root, leaf_hash, &proof);
+            writeln!(out, "{}", if valid { "valid" } else { "invalid" })
+                .map_err(|e| e.to_string())?;
+            return Ok(valid);
+        }
+        _ => return Err(USAGE.to_owned()),
+    }
+    Ok(true)
+}
+
+/// Opens the file named by the remaining arguments, or stdin.
+fn input<'a>(rest: &[&str], stdin: impl BufRead + 'a) -> Result<Box<dyn BufRead + 'a>, String> {
+    match rest {
+        [] | ["-"] => Ok(Box::new(stdin)),
+        [path] => {
+            let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+            Ok(Box::new(BufReader::new(file)))
+        }
+        _ => Err(USAGE.to_owned()),
+    }
+}
+
+fn read_tree(input: impl BufRead) -> Result<Tree, String> {
+    let mut tree = Tree::new();
+    for (number, line) in input.lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        tree.insert_hex(line)
+            .map_err(|e| format!("Line {}: {}", number + 1, e))?;
+    }
+    Ok(tree)
+}
+
+fn parse(value: &str, name: &str) -> Result<[u8; 32], String> {
+    hex_to_bytes32(value).map_err(|e| format!("Invalid {} {}: {}", name, value, e))
+}
+
+fn prefixed(value: [u8; 32]) -> String {
+    "0x".to_owned() + &faster_hex::hex_string(&value).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const STATE: &str = "0x000000000000000000000000ccc0000000000000000000000000000000000000364b4e94b854e94d5f35bf42698696b3064c91aaef39831bfd6c296aa6d1c33f000000000000000000000000def0000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001abcdef0000000000000000000000000000000000000000000000000000000000";
+    const TRANSFER_ID: &str = "0x364b4e94b854e94d5f35bf42698696b3064c91aaef39831bfd6c296aa6d1c33f";
+    const LEAF_HASH: &str = "0x8b50ff7d7ad3e4f693f210af3a2f025371d588c18d44a52bd7bdf610b1b1b799";
+
+    /// Runs a command with the transfer states on stdin, returning the output.
+    fn run_with(command: &[&str], states: &[&str]) -> Result<(bool, String), String> {
+        let args: Vec<String> = command.iter().map(|s| s.to_string()).collect();
+        let stdin = states.join("\n");
+        let mut out = Vec::new();
+        let ok = run(&args, stdin.as_bytes(), &mut out)?;
+        Ok((ok, String::from_utf8(out).unwrap()))
+    }
+
+    #[test]
+    fn single_transfer() {
+        // A lone leaf is the root
+        let (_, root) = run_with(&["root"], &[STATE, ""]).unwrap();
+        assert_eq!(root, format!("{}\n", LEAF_HASH));
+
+        let (_, leaves) = run_with(&["leaves", "-"], &[STATE]).unwrap();
+        assert_eq!(leaves, format!("{} {}\n", TRANSFER_ID, LEAF_HASH));
+
+        let (_, proof) = run_with(&["proof", TRANSFER_ID], &[STATE]).unwrap();
+        assert_eq!(proof, "");
+
+        let verified = run_with(&["verify", LEAF_HASH, LEAF_HASH], &[]);
+        assert_eq!(verified, Ok((true, "valid\n".to_owned())));
+    }
+
+    #[test]
+    fn proof_verifies() {
+        let mut other = STATE.to_owned();
+        other.replace_range(66..68, "ff");
+        let states = [STATE, other.as_str()];
+
+        let (_, root) = run_with(&["root"], &states).unwrap();
+        let (_, proof) = run_with(&["proof", TRANSFER_ID], &states).unwrap();
+        let mut args = vec!["verify", root.trim(), LEAF_HASH];
+        args.extend(proof.lines());
+        assert_eq!(args.len(), 4);
+        assert_eq!(run_with(&args, &[]), Ok((true, "valid\n".to_owned())));
+
+        args[2] = TRANSFER_ID;
+        assert_eq!(run_with(&args, &[]), Ok((false, "invalid\n".to_owned())));
+    }
+
+    #[test]
+    fn reads_files() {
+        let path = env::temp_dir().join(format!("vmt-test-{}", process::id()));
+        fs::write(&path, format!("{}\n\n{}\n", STATE, STATE)).unwrap();
+        let result = run_with(&["root", path.to_str().unwrap()], &[]);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(result, Ok((true, format!("{}\n", LEAF_HASH))));
+
+        assert!(run_with(&["root", "/nonexistent/transfers"], &[]).is_err());
+    }
+
+    #[test]
+    fn reports_errors() {
+        assert_eq!(
+            run_with(&["root"], &[STATE, "0x1234"]),
+            Err("Line 2: Expected a length of 770 but got 6".to_owned())
+        );
+        assert!(run_with(&["proof", "0x00"], &[STATE]).is_err());
+        assert!(run_with(&["proof", LEAF_HASH], &[STATE]).is_err());
+        assert!(run_with(&["frobnicate"], &[]).is_err());
+        assert!(run_with(&["root", "a", "b"], &[]).is_err());
+    }
+}
@@ -0,0 +1,107 @@
+use super::{Bytes32, MerkleHasher, Node};
+
+/// The internal levels of a tree as of the last time its root was calculated.
+///
+/// Inserting or deleting a leaf shifts every leaf after it, so each node that
+/// covers a leaf at or after that index is stale. Rather than updating anything
+/// at that point, only the leftmost changed index is remembered and the stale
+/// nodes are recomputed on the next call to `update`. Replacing a leaf in place
+/// only makes the nodes on its path stale, so those leaves are remembered
+/// separately.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Cache {
+    /// Level 1 (the parents of the leaves) upwards, ending with the root.
+    levels: Vec<Vec<Bytes32>>,
+    /// Leaves from this index onwards have changed since the last update.
+    dirty: usize,
+    /// Leaves before `dirty` that were replaced in place since the last update.
+    replaced: Vec<usize>,
+}
+
+impl Cache {
+    /// Marks every leaf from the index onwards as changed.
+    pub fn invalidate(&mut self, index: usize) {
+        self.dirty = self.dirty.min(index);
+    }
+
+    /// Marks the leaf at the index as replaced, leaving the other leaves where
+    /// they were.
+    pub fn invalidate_path(&mut self, index: usize) {
+        if index < self.dirty {
+            self.replaced.push(index);
+        }
+    }
+
+    /// Brings the levels up to date with the leaves and returns the root.
+    pub fn update<H: MerkleHasher>(&mut self, leaves: &[Node]) -> Bytes32 {
+        if leaves.is_empty() {
+            self.levels.clear();
+            self.dirty = 0;
+            self.replaced.clear();
+            return Default::default();
+        }
+
+        let mut width = leaves.len();
+        let mut dirty = self.dirty.min(width);
+        // Leaves only move at or after `dirty`, so the ones replaced before it
+        // are still at the same index.
+        let mut replaced = std::mem::take(&mut self.replaced);
+        replaced.retain(|&i| i < dirty);
+        replaced.sort_unstable();
+        let mut level = 0;
+        while width > 1 {
+            let parent_width = width.div_ceil(2);
+            // Parents only go stale when one of their children does.
+            let parent_dirty = dirty / 2;
+
+            if self.levels.len() == level {
+                self.levels.push(Vec::new());
+            }
+            let (below, above) = self.levels.split_at_mut(level);
+            let child = |i: usize| match below.last() {
+                Some(children) => children[i],
+                None => leaves[i].hash,
+            };
+            let parents = &mut above[0];
+            // Move the replaced indices up to their parents, keeping them sorted
+            for i in replaced.iter_mut() {
+                *i /= 2;
+            }
+            replaced.dedup();
+            for &i in replaced.iter().take_while(|&&i| i < parent_dirty) {
+                parents[i] = H::combine(&child(i * 2), &child(i * 2 + 1));
+            }
+            parents.truncate(parent_dirty);
+            for i in parent_dirty..parent_width {
+                let a = child(i * 2);
+                if i * 2 + 1 < width {
+                    parents.push(H::combine(&a, &child(i * 2 + 1)));
+                } else {
+                    parents.push(a);
+                }
+            }
+
+            width = parent_width;
+            dirty = parent_dirty;
+            level += 1;
+        }
+
+        self.levels.truncate(level);
+        self.dirty = leaves.len();
+
+        match self.levels.last() {
+            Some(top) => top[0],
+            None => leaves[0].hash,
+        }
+    }
+
+    /// The node at the given level and index, where level 0 is the leaves.
+    /// The levels must be up to date with the leaves.
+    pub fn node(&self, leaves: &[Node], level: usize, index: usize) -> Bytes32 {
+        debug_assert_eq!(self.dirty, leaves.len(), "stale cache");
+        match level {
+            0 => leaves[index].hash,
+            _ => self.levels[level - 1][index],
+        }
+    }
+}
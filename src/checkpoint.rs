@@ -0,0 +1,300 @@
+//! Undoing inserts and deletes back to an earlier point.
+//!
+//! While any checkpoint is open, every change to the leaves is recorded in a
+//! journal along with what is needed to reverse it. Rolling back replays the
+//! journal backwards, so it costs as much as the changes it undoes and does no
+//! hashing until the next call to `root`.
+
+use super::{Bytes32, Error, Kept, Node, Tree};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Numbers each journal, so that a checkpoint only works on its own tree.
+static NEXT_JOURNAL: AtomicU64 = AtomicU64::new(0);
+
+/// A point to roll a tree back to, returned by `Tree::checkpoint`.
+///
+/// Checkpoints nest. Rolling back or committing one also closes any taken
+/// after it, whose tokens are then rejected, as are tokens from other trees.
+///
+/// Dropping a checkpoint does not close it, since it holds no reference to its
+/// tree. The tree keeps recording changes until that checkpoint or one taken
+/// before it is committed or rolled back, so a checkpoint that is no longer
+/// needed should be committed.
+#[must_use]
+#[derive(Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    journal: u64,
+    id: u64,
+    depth: usize,
+}
+
+/// A change to the leaves, with what is needed to reverse it.
+#[derive(Debug, Clone)]
+pub(crate) enum Change {
+    /// The leaf with this transfer id was inserted.
+    Inserted(Bytes32),
+    /// This leaf was deleted, and this was kept about it.
+    Deleted(Node, Kept),
+    /// This leaf was replaced, and this was kept about it.
+    Replaced(Node, Kept),
+}
+
+impl Change {
+    fn transfer_id(&self) -> &Bytes32 {
+        match self {
+            Change::Inserted(transfer_id) => transfer_id,
+            Change::Deleted(node, _) | Change::Replaced(node, _) => &node.transfer_id,
+        }
+    }
+}
+
+/// Not `Clone`, since the checkpoints of a tree cannot be used on its clones.
+/// A clone starts with a new journal instead.
+#[derive(Debug)]
+pub(crate) struct Journal {
+    /// Unique to this journal.
+    nonce: u64,
+    changes: Vec<Change>,
+    /// The id of each open checkpoint and the number of changes before it.
+    open: Vec<(u64, usize)>,
+    next_id: u64,
+}
+
+impl Default for Journal {
+    fn default() -> Self {
+        Self {
+            nonce: NEXT_JOURNAL.fetch_add(1, Ordering::Relaxed),
+            changes: Vec::new(),
+            open: Vec::new(),
+            next_id: 0,
+        }
+    }
+}
+
+impl Journal {
+    /// Remembers a change if there is a checkpoint it could be rolled back to.
+    pub fn record(&mut self, change: Change) {
+        if !self.open.is_empty() {
+            self.changes.push(change);
+        }
+    }
+
+    /// Closes the checkpoint and those after it, returning the number of
+    /// changes that were made before it.
+    fn close(&mut self, checkpoint: Checkpoint) -> Result<usize, Error> {
+        if checkpoint.journal != self.nonce {
+            return Err(Error::UnknownCheckpoint);
+        }
+        match self.open.get(checkpoint.depth) {
+            Some(&(id, start)) if id == checkpoint.id => {
+                self.open.truncate(checkpoint.depth);
+                Ok(start)
+            }
+            _ => Err(Error::UnknownCheckpoint),
+        }
+    }
+}
+
+impl<H> Tree<H> {
+    /// Starts recording changes so that they can be undone with `rollback`.
+    pub fn checkpoint(&mut self) -> Checkpoint {
+        let journal = &mut self.journal;
+        let checkpoint = Checkpoint {
+            journal: journal.nonce,
+            id: journal.next_id,
+            depth: journal.open.len(),
+        };
+        journal.next_id += 1;
+        journal.open.push((checkpoint.id, journal.changes.len()));
+        checkpoint
+    }
+
+    /// Undoes every insert and delete since the checkpoint was taken,
+    /// including those kept by committing checkpoints nested in it.
+    ///
+    /// Fails with `Error::JournalOutOfStep` if a recorded change does not fit
+    /// the leaves, which only happens if they were changed without going
+    /// through the journal. The later changes are undone by then, and the rest
+    /// are dropped.
+    pub fn rollback(&mut self, checkpoint: Checkpoint) -> Result<(), Error> {
+        let start = self.journal.close(checkpoint)?;
+        let cache = self.cache.get_mut().unwrap();
+        let leaf_data = &mut self.leaf_data;
+        for change in self.journal.changes.drain(start..).rev() {
+            let transfer_id = *change.transfer_id();
+            let position = self
+                .leaves
+                .binary_search_by_key(&change.transfer_id(), |n| &n.transfer_id);
+            match (change, position) {
+                (Change::Inserted(_), Ok(i)) => {
+                    let removed = self.leaves.remove(i);
+                    leaf_data.on_removed(&removed);
+                    cache.invalidate(i);
+                }
+                (Change::Deleted(node, kept), Err(i)) => {
+                    leaf_data.on_added(&node, kept);
+                    self.leaves.insert(i, node);
+                    cache.invalidate(i);
+                }
+                (Change::Replaced(node, kept), Ok(i)) => {
+                    leaf_data.on_removed(&self.leaves[i]);
+                    leaf_data.on_added(&node, kept);
+                    self.leaves[i] = node;
+                    cache.invalidate_path(i);
+                }
+                _ => return Err(Error::JournalOutOfStep { transfer_id }),
+            }
+        }
+        Ok(())
+    }
+
+    /// Keeps every insert and delete since the checkpoint was taken. They can
+    /// still be undone by rolling back a checkpoint taken before this one.
+    pub fn commit(&mut self, checkpoint: Checkpoint) -> Result<(), Error> {
+        self.journal.close(checkpoint)?;
+        if self.journal.open.is_empty() {
+            self.journal.changes.clear();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{encoded_transfer, transfer, tree};
+
+    #[test]
+    fn rollback_undoes_everything() {
+        let mut tree = tree(6);
+        let leaves = tree.leaves.clone();
+        let root = tree.root();
+
+        let checkpoint = tree.checkpoint();
+        tree.insert_hex(&encoded_transfer(10)).unwrap();
+        tree.root();
+        tree.delete_id(leaves[0].transfer_id);
+        tree.delete_id(leaves[3].transfer_id);
+        let mut state = transfer(2);
+        state.transfer_timeout += 1;
+        tree.upsert_state(&state).unwrap();
+        tree.insert_many_hex((11..15).map(encoded_transfer))
+            .unwrap();
+        tree.delete_many(leaves[4..].iter().map(|n| n.transfer_id));
+        // A failed batch changes nothing, so there is nothing to record
+        assert!(tree.insert_many_hex(&["0x"]).is_err());
+        assert_ne!(tree.root(), root);
+
+        tree.rollback(checkpoint).unwrap();
+        assert_eq!(tree.leaves, leaves);
+        assert_eq!(tree.root(), root);
+        assert!(tree.journal.changes.is_empty());
+    }
+
+    #[test]
+    fn nested_checkpoints() {
+        let mut tree = tree(3);
+        let root = tree.root();
+
+        let outer = tree.checkpoint();
+        tree.insert_hex(&encoded_transfer(3)).unwrap();
+        let after_outer = tree.root();
+
+        // Rolling back the inner checkpoint keeps the outer changes
+        let inner = tree.checkpoint();
+        tree.insert_hex(&encoded_transfer(4)).unwrap();
+        tree.rollback(inner).unwrap();
+        assert_eq!(tree.root(), after_outer);
+
+        // Committing it leaves them to the outer checkpoint
+        let inner = tree.checkpoint();
+        tree.insert_hex(&encoded_transfer(4)).unwrap();
+        tree.commit(inner).unwrap();
+        assert_eq!(tree.leaves.len(), 5);
+
+        tree.rollback(outer).unwrap();
+        assert_eq!(tree.leaves.len(), 3);
+        assert_eq!(tree.root(), root);
+
+        // Committing the outermost checkpoint forgets the changes
+        let outer = tree.checkpoint();
+        tree.insert_hex(&encoded_transfer(3)).unwrap();
+        tree.commit(outer).unwrap();
+        assert!(tree.journal.changes.is_empty());
+        assert_eq!(tree.root(), after_outer);
+    }
+
+    #[test]
+    fn closed_checkpoints_are_rejected() {
+        let mut tree = tree(3);
+        let outer = tree.checkpoint();
+        let inner = tree.checkpoint();
+        tree.insert_hex(&encoded_transfer(3)).unwrap();
+        tree.rollback(outer).unwrap();
+        assert_eq!(tree.commit(inner), Err(Error::UnknownCheckpoint));
+
+        // Even once there is a new checkpoint at the same depth
+        let outer = tree.checkpoint();
+        let inner = tree.checkpoint();
+        let nested = tree.checkpoint();
+        tree.rollback(inner).unwrap();
+        let _inner = tree.checkpoint();
+        let _replacement = tree.checkpoint();
+        assert_eq!(tree.rollback(nested), Err(Error::UnknownCheckpoint));
+        tree.commit(outer).unwrap();
+    }
+
+    #[test]
+    fn checkpoints_of_other_trees_are_rejected() {
+        let mut other = tree(3);
+        let mut tree = tree(3);
+        let checkpoint = other.checkpoint();
+        tree.insert_hex(&encoded_transfer(3)).unwrap();
+        assert_eq!(tree.rollback(checkpoint), Err(Error::UnknownCheckpoint));
+        assert_eq!(tree.leaves.len(), 4);
+
+        // Nor does a clone take the checkpoints of the original
+        let checkpoint = tree.checkpoint();
+        let mut copy = tree.clone();
+        copy.delete_id(copy.leaves[0].transfer_id);
+        assert_eq!(copy.rollback(checkpoint), Err(Error::UnknownCheckpoint));
+        assert_eq!(copy.leaves.len(), 3);
+        assert!(copy.journal.open.is_empty());
+        assert_eq!(tree.journal.open.len(), 1);
+    }
+
+    #[test]
+    fn dropped_checkpoints_stay_open() {
+        let mut tree = tree(3);
+        let outer = tree.checkpoint();
+        let _ = tree.checkpoint();
+        tree.insert_hex(&encoded_transfer(3)).unwrap();
+        assert_eq!(tree.journal.open.len(), 2);
+        assert_eq!(tree.journal.changes.len(), 1);
+
+        // Closing an earlier checkpoint closes the dropped one too
+        tree.commit(outer).unwrap();
+        assert!(tree.journal.open.is_empty());
+        assert!(tree.journal.changes.is_empty());
+        tree.insert_hex(&encoded_transfer(4)).unwrap();
+        assert!(tree.journal.changes.is_empty());
+    }
+
+    #[test]
+    fn rollback_reports_a_journal_out_of_step() {
+        let mut tree = tree(3);
+        let checkpoint = tree.checkpoint();
+        tree.insert_hex(&encoded_transfer(3)).unwrap();
+        let transfer_id = transfer(3).transfer_id;
+
+        // Removing the leaf behind the journal's back
+        let i = tree.index_of(transfer_id).unwrap();
+        let node = tree.leaves.remove(i);
+        tree.leaf_data.on_removed(&node);
+
+        assert_eq!(
+            tree.rollback(checkpoint),
+            Err(Error::JournalOutOfStep { transfer_id })
+        );
+    }
+}
@@ -0,0 +1,331 @@
+//! Finding the transfers that differ between two trees.
+//!
+//! With both trees at hand `Tree::diff` walks their leaves side by side. When
+//! the other tree belongs to a counterparty, the differences can instead be
+//! narrowed down by exchanging hashes of ranges of transfer ids:
+//!
+//! 1. Ask the counterparty to `summarize` `IdRange::full()`.
+//! 2. `reconcile` each summary received. Ranges that hash the same are done,
+//!    small ranges come with their leaves and are compared directly, and the
+//!    rest are split in two at a transfer id chosen by the counterparty.
+//! 3. Ask for summaries of the ranges returned, and repeat until there are
+//!    none left.
+//!
+//! Each split halves the number of the counterparty's leaves in a range, so a
+//! handful of differences are found in O(log n) rounds without either side
+//! sending its whole set.
+
+use super::{subtree_root, Bytes32, Error, MerkleHasher, Node, Tree};
+
+/// The transfer ids present in only one of two trees, or in both with
+/// different leaf hashes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Diff {
+    /// In the other tree but not this one.
+    pub added: Vec<Bytes32>,
+    /// In this tree but not the other.
+    pub removed: Vec<Bytes32>,
+    /// In both trees with different leaf hashes.
+    pub changed: Vec<Bytes32>,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    /// Adds the differences between a run of leaves and the transfer ids and
+    /// leaf hashes of another, both sorted by transfer id.
+    fn extend(&mut self, ours: &[Node], theirs: impl IntoIterator<Item = (Bytes32, Bytes32)>) {
+        let mut ours = ours.iter().peekable();
+        for (transfer_id, hash) in theirs {
+            while let Some(leaf) = ours.next_if(|leaf| leaf.transfer_id < transfer_id) {
+                self.removed.push(leaf.transfer_id);
+            }
+            match ours.next_if(|leaf| leaf.transfer_id == transfer_id) {
+                Some(leaf) if leaf.hash != hash => self.changed.push(transfer_id),
+                Some(_) => {}
+                None => self.added.push(transfer_id),
+            }
+        }
+        self.removed.extend(ours.map(|leaf| leaf.transfer_id));
+    }
+}
+
+/// The transfer ids from `start` up to but not including `end`, or through
+/// the highest id when there is no `end`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdRange {
+    pub start: Bytes32,
+    pub end: Option<Bytes32>,
+}
+
+impl IdRange {
+    /// Every transfer id.
+    pub fn full() -> Self {
+        IdRange {
+            start: Bytes32::default(),
+            end: None,
+        }
+    }
+}
+
+/// Ranges with at most this many leaves are summarized by the leaves
+/// themselves.
+const SUMMARY_LEAVES: usize = 4;
+
+/// What one tree holds in a range of transfer ids.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeSummary {
+    pub range: IdRange,
+    /// The root of the leaves in the range, hashed as if they were a tree of
+    /// their own.
+    pub hash: Bytes32,
+    pub count: usize,
+    pub contents: RangeContents,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RangeContents {
+    /// The transfer id and hash of every leaf, for small ranges.
+    Leaves(Vec<(Bytes32, Bytes32)>),
+    /// The transfer id at which to split a larger range, which leaves about
+    /// half of the leaves on each side.
+    Split(Bytes32),
+}
+
+impl<H: MerkleHasher> Tree<H> {
+    /// Compares the leaves of two trees, from the point of view of this one.
+    pub fn diff(&self, other: &Self) -> Diff {
+        let mut diff = Diff::default();
+        diff.extend(
+            &self.leaves,
+            other.leaves.iter().map(|n| (n.transfer_id, n.hash)),
+        );
+        diff
+    }
+
+    /// Describes the leaves in the range for a counterparty to `reconcile`.
+    pub fn summarize(&self, range: IdRange) -> RangeSummary {
+        let (start, leaves) = self.range(&range);
+        let contents = if leaves.len() <= SUMMARY_LEAVES {
+            RangeContents::Leaves(leaves.iter().map(|n| (n.transfer_id, n.hash)).collect())
+        } else {
+            RangeContents::Split(leaves[leaves.len() / 2].transfer_id)
+        };
+        RangeSummary {
+            range,
+            hash: self.range_root(start, leaves),
+            count: leaves.len(),
+            contents,
+        }
+    }
+
+    /// Compares a counterparty's summary with this tree, adding what differs
+    /// to `diff` when the summary has the leaves, and otherwise returning the
+    /// ranges to ask about next.
+    ///
+    /// Fails with `Error::UnsortedLeaves` if the leaves of the summary are not
+    /// strictly ordered by transfer id, without changing `diff`.
+    pub fn reconcile(
+        &self,
+        summary: &RangeSummary,
+        diff: &mut Diff,
+    ) -> Result<Vec<IdRange>, Error> {
+        let (start, leaves) = self.range(&summary.range);
+        if leaves.len() == summary.count && self.range_root(start, leaves) == summary.hash {
+            return Ok(Vec::new());
+        }
+        Ok(match &summary.contents {
+            RangeContents::Leaves(theirs) => {
+                if let Some(index) = theirs.windows(2).position(|pair| pair[0].0 >= pair[1].0) {
+                    return Err(Error::UnsortedLeaves { index: index + 1 });
+                }
+                diff.extend(leaves, theirs.iter().copied());
+                Vec::new()
+            }
+            RangeContents::Split(middle) => vec![
+                IdRange {
+                    start: summary.range.start,
+                    end: Some(*middle),
+                },
+                IdRange {
+                    start: *middle,
+                    end: summary.range.end,
+                },
+            ],
+        })
+    }
+
+    /// The index of the first leaf in the range, and the leaves in it.
+    fn range(&self, range: &IdRange) -> (usize, &[Node]) {
+        let start = self.leaves.partition_point(|n| n.transfer_id < range.start);
+        let end = match range.end {
+            Some(end) => self.leaves.partition_point(|n| n.transfer_id < end),
+            None => self.leaves.len(),
+        };
+        (start, &self.leaves[start..end.max(start)])
+    }
+
+    /// The root of the leaves from `start`, read from the cached levels when
+    /// they are exactly the leaves under one node of the tree.
+    fn range_root(&self, start: usize, leaves: &[Node]) -> Bytes32 {
+        let level = leaves.len().next_power_of_two().trailing_zeros() as usize;
+        let whole_node = start.is_multiple_of(1 << level)
+            && (leaves.len() == 1 << level || start + leaves.len() == self.leaves.len());
+        if leaves.is_empty() || !whole_node {
+            return subtree_root::<H>(leaves);
+        }
+        let mut cache = self.cache.lock().unwrap();
+        cache.update::<H>(&self.leaves);
+        cache.node(&self.leaves, level, start >> level)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{encoded_transfer, rand};
+    use crate::{CoreTransferState, Keccak256};
+
+    /// Two trees sharing most transfers, and what tells them apart.
+    fn trees(size: u64) -> (Tree, Tree, Diff) {
+        let mut ours = Tree::new();
+        let mut theirs = Tree::new();
+        let mut expected = Diff::default();
+        for i in 0..size {
+            let transfer = encoded_transfer(i);
+            let transfer_id = CoreTransferState::from_hex(&transfer).unwrap().transfer_id;
+            match rand(i) % 50 {
+                0 => {
+                    ours.insert_hex(&transfer).unwrap();
+                    expected.removed.push(transfer_id);
+                }
+                1 => {
+                    theirs.insert_hex(&transfer).unwrap();
+                    expected.added.push(transfer_id);
+                }
+                2 => {
+                    let mut state = CoreTransferState::from_hex(&transfer).unwrap();
+                    ours.insert_state(&state).unwrap();
+                    state.transfer_timeout += 1;
+                    theirs.insert_state(&state).unwrap();
+                    expected.changed.push(transfer_id);
+                }
+                _ => {
+                    ours.insert_hex(&transfer).unwrap();
+                    theirs.insert_hex(&transfer).unwrap();
+                }
+            }
+        }
+        expected.added.sort_unstable();
+        expected.removed.sort_unstable();
+        expected.changed.sort_unstable();
+        (ours, theirs, expected)
+    }
+
+    #[test]
+    fn diff_finds_every_difference() {
+        let (ours, theirs, expected) = trees(500);
+        assert!(!expected.is_empty());
+        assert_eq!(ours.diff(&theirs), expected);
+        assert!(ours.diff(&ours).is_empty());
+
+        let reversed = theirs.diff(&ours);
+        assert_eq!(reversed.added, expected.removed);
+        assert_eq!(reversed.removed, expected.added);
+        assert_eq!(reversed.changed, expected.changed);
+    }
+
+    #[test]
+    fn reconcile_finds_every_difference() {
+        for &size in &[0, 1, 3, 10, 100, 2000] {
+            let (ours, theirs, expected) = trees(size);
+
+            let mut diff = Diff::default();
+            let mut ranges = vec![IdRange::full()];
+            let mut rounds = 0;
+            let mut messages = 0;
+            while !ranges.is_empty() {
+                rounds += 1;
+                messages += ranges.len();
+                ranges = ranges
+                    .into_iter()
+                    .flat_map(|range| ours.reconcile(&theirs.summarize(range), &mut diff).unwrap())
+                    .collect();
+            }
+
+            diff.added.sort_unstable();
+            diff.removed.sort_unstable();
+            diff.changed.sort_unstable();
+            assert_eq!(diff, expected);
+
+            // Each difference costs a path of summaries down to a leaf
+            let depth = 64 - (size.max(1)).leading_zeros() as usize;
+            assert!(rounds <= depth + 1);
+            let differences =
+                expected.added.len() + expected.removed.len() + expected.changed.len();
+            assert!(messages <= 1 + 2 * depth * differences);
+        }
+    }
+
+    #[test]
+    fn identical_trees_take_one_summary() {
+        let (ours, _, _) = trees(100);
+        let summary = ours.summarize(IdRange::full());
+        assert_eq!(summary.hash, ours.root());
+        assert_eq!(ours.reconcile(&summary, &mut Diff::default()), Ok(vec![]));
+    }
+
+    /// Ranges that line up with a node of the tree take their hash from the
+    /// cache, and must agree with hashing the leaves.
+    #[test]
+    fn summary_hashes_match_leaves() {
+        let (ours, _, _) = trees(100);
+        let ids: Vec<_> = ours.leaves.iter().map(|n| n.transfer_id).collect();
+        let len = ids.len();
+        let ranges = [
+            (0, 1),
+            (0, 64),
+            (64, 80),
+            (64, len),
+            (len - 1, len),
+            (3, 17),
+            (5, 6),
+        ];
+        for (start, end) in ranges {
+            let range = IdRange {
+                start: ids[start],
+                end: ids.get(end).copied(),
+            };
+            let summary = ours.summarize(range);
+            assert_eq!(summary.count, end - start);
+            assert_eq!(summary.hash, subtree_root::<Keccak256>(&ours.leaves[start..end]));
+        }
+    }
+
+    #[test]
+    fn reconcile_rejects_unsorted_leaves() {
+        let (ours, theirs, _) = trees(10);
+        let mut summary = theirs.summarize(IdRange::full());
+        summary.hash = Bytes32::default();
+        let leaves: Vec<_> = theirs.leaves.iter().map(|n| (n.transfer_id, n.hash)).collect();
+
+        let mut swapped = leaves[..3].to_vec();
+        swapped.swap(1, 2);
+        summary.contents = RangeContents::Leaves(swapped);
+        let mut diff = Diff::default();
+        assert_eq!(
+            ours.reconcile(&summary, &mut diff),
+            Err(Error::UnsortedLeaves { index: 2 })
+        );
+
+        let repeated = vec![leaves[0], leaves[1], leaves[1]];
+        summary.contents = RangeContents::Leaves(repeated);
+        assert_eq!(
+            ours.reconcile(&summary, &mut diff),
+            Err(Error::UnsortedLeaves { index: 2 })
+        );
+        assert!(diff.is_empty());
+    }
+}
@@ -1,10 +1,119 @@
+use super::{
+    format::{fmt_address, hex_encode},
+    Address, Bytes32,
+};
 use std::error;
 use std::fmt;
+use wasm_bindgen::JsValue;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Error {
-    InvalidFormat,
-    DuplicateTransferID,
+    /// The input has the wrong length. For hex strings this counts characters
+    /// including the 0x prefix, otherwise bytes.
+    WrongLength { expected: usize, got: usize },
+    /// A hex string does not start with 0x.
+    MissingPrefix,
+    /// A hex string has a character that is not a hex digit at this index.
+    InvalidHexChar { index: usize },
+    /// A field of a core transfer state that should be an address has
+    /// non-zero padding.
+    InvalidAddress { field: &'static str },
+    /// A transfer is already in the tree with a different state.
+    DuplicateTransferID {
+        transfer_id: Bytes32,
+        existing: Bytes32,
+        inserted: Bytes32,
+    },
+    /// A snapshot does not start with the expected magic bytes, or its header
+    /// is malformed.
+    InvalidSnapshotHeader,
+    /// A snapshot was written by an unknown version of the format.
+    UnsupportedSnapshotVersion { version: u8 },
+    /// A snapshot was written with an older layout than the `current` one,
+    /// which can no longer be restored.
+    OutdatedSnapshotVersion { version: u8, current: u8 },
+    /// The leaf at this index of a snapshot or range summary is not strictly
+    /// after the one before.
+    UnsortedLeaves { index: usize },
+    /// The leaf at this index of a snapshot is of an unknown kind, or of a
+    /// kind the header has no more of.
+    InvalidSnapshotLeaf { index: usize },
+    /// The leaves of a snapshot do not hash to the root stored with them.
+    RootMismatch {
+        expected: Bytes32,
+        computed: Bytes32,
+    },
+    /// A snapshot does not hash to the checksum at its end, so its header or
+    /// the fields kept for its leaves were changed.
+    ChecksumMismatch,
+    /// Some items of a batch failed, each given with its position in the
+    /// batch. None of the batch was applied.
+    Batch { errors: Vec<(usize, Error)> },
+    /// A checkpoint was already committed or rolled back.
+    UnknownCheckpoint,
+    /// Rolling back found a recorded change to this transfer that does not
+    /// fit the leaves.
+    JournalOutOfStep { transfer_id: Bytes32 },
+    /// There is no transfer with this id in the tree.
+    NotFound { transfer_id: Bytes32 },
+    /// A transfer state belongs to a different channel than the tree.
+    ChannelMismatch { expected: Address, got: Address },
+    /// A string that should be a decimal integer has a character that is not
+    /// a digit, is empty or does not fit in 256 bits.
+    InvalidDecimal,
+    /// Multiproofs can only be made for trees whose hasher sorts pairs.
+    MultiproofNeedsSortedPairs,
+    /// The transfer is promoted past a level where it pairs with a node built
+    /// from other transfers of the same multiproof, which the verifier's
+    /// first-in first-out queue cannot express.
+    MultiproofOrder { transfer_id: Bytes32 },
+    /// Non-inclusion proofs can only be made for trees whose hasher keeps the
+    /// order of pairs.
+    NonInclusionNeedsUnsortedPairs,
+    /// The transfer is in the tree, so it has no non-inclusion proof.
+    TransferIncluded { transfer_id: Bytes32 },
+    /// The state of this transfer was not kept, as it was inserted while the
+    /// tree did not retain states.
+    StateNotRetained { transfer_id: Bytes32 },
+    /// A JS value that should be a `Uint8Array` is something else.
+    NotBytes,
+    /// The item at this index of a JS array should be a string but is
+    /// something else.
+    NotString { index: usize },
+}
+
+impl Error {
+    /// A stable identifier for the kind of error, which is given to JS as
+    /// the `code` field.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::WrongLength { .. } => "WRONG_LENGTH",
+            Error::MissingPrefix => "MISSING_PREFIX",
+            Error::InvalidHexChar { .. } => "INVALID_HEX_CHAR",
+            Error::InvalidAddress { .. } => "INVALID_ADDRESS",
+            Error::DuplicateTransferID { .. } => "DUPLICATE_TRANSFER_ID",
+            Error::InvalidSnapshotHeader => "INVALID_SNAPSHOT_HEADER",
+            Error::UnsupportedSnapshotVersion { .. } => "UNSUPPORTED_SNAPSHOT_VERSION",
+            Error::OutdatedSnapshotVersion { .. } => "OUTDATED_SNAPSHOT_VERSION",
+            Error::UnsortedLeaves { .. } => "UNSORTED_LEAVES",
+            Error::InvalidSnapshotLeaf { .. } => "INVALID_SNAPSHOT_LEAF",
+            Error::RootMismatch { .. } => "ROOT_MISMATCH",
+            Error::ChecksumMismatch => "CHECKSUM_MISMATCH",
+            Error::Batch { .. } => "BATCH",
+            Error::UnknownCheckpoint => "UNKNOWN_CHECKPOINT",
+            Error::JournalOutOfStep { .. } => "JOURNAL_OUT_OF_STEP",
+            Error::NotFound { .. } => "NOT_FOUND",
+            Error::ChannelMismatch { .. } => "CHANNEL_MISMATCH",
+            Error::InvalidDecimal => "INVALID_DECIMAL",
+            Error::MultiproofNeedsSortedPairs => "MULTIPROOF_NEEDS_SORTED_PAIRS",
+            Error::MultiproofOrder { .. } => "MULTIPROOF_ORDER",
+            Error::NonInclusionNeedsUnsortedPairs => "NON_INCLUSION_NEEDS_UNSORTED_PAIRS",
+            Error::TransferIncluded { .. } => "TRANSFER_INCLUDED",
+            Error::StateNotRetained { .. } => "STATE_NOT_RETAINED",
+            Error::NotBytes => "NOT_BYTES",
+            Error::NotString { .. } => "NOT_STRING",
+        }
+    }
 }
 
 impl error::Error for Error {}
@@ -12,8 +121,185 @@ impl error::Error for Error {}
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Error::InvalidFormat => write!(f, "Invalid format for encoded core transfer app"),
-            Error::DuplicateTransferID => write!(f, "A transfer must have a canonical state"),
+            Error::WrongLength { expected, got } => {
+                write!(f, "Expected a length of {} but got {}", expected, got)
+            }
+            Error::MissingPrefix => write!(f, "Hex string must start with 0x"),
+            Error::InvalidHexChar { index } => {
+                write!(f, "Invalid hex character at index {}", index)
+            }
+            Error::InvalidAddress { field } => {
+                write!(
+                    f,
+                    "Field {} of the core transfer state is not an address",
+                    field
+                )
+            }
+            Error::DuplicateTransferID { transfer_id, .. } => write!(
+                f,
+                "A transfer must have a canonical state, but 0x{} has two",
+                hex_encode(*transfer_id)
+            ),
+            Error::InvalidSnapshotHeader => write!(f, "Not a tree snapshot, or its header is malformed"),
+            Error::UnsupportedSnapshotVersion { version } => {
+                write!(f, "Unsupported tree snapshot version {}", version)
+            }
+            Error::OutdatedSnapshotVersion { version, current } => write!(
+                f,
+                "Tree snapshot version {} predates the current version {} and cannot be restored, \
+                 so the tree has to be rebuilt from its transfer states",
+                version, current
+            ),
+            Error::UnsortedLeaves { index } => write!(
+                f,
+                "Leaf {} of the snapshot or summary is out of order or repeated",
+                index
+            ),
+            Error::InvalidSnapshotLeaf { index } => write!(
+                f,
+                "Leaf {} of the snapshot is of an unknown kind or does not match the header",
+                index
+            ),
+            Error::RootMismatch { expected, computed } => write!(
+                f,
+                "Snapshot root 0x{} does not match its leaves, which hash to 0x{}",
+                hex_encode(*expected),
+                hex_encode(*computed)
+            ),
+            Error::ChecksumMismatch => write!(f, "The snapshot does not match its checksum"),
+            Error::Batch { errors } => match errors.first() {
+                Some((position, error)) => write!(
+                    f,
+                    "{} items of the batch failed, starting with item {}: {}",
+                    errors.len(),
+                    position,
+                    error
+                ),
+                None => write!(f, "The batch failed"),
+            },
+            Error::UnknownCheckpoint => {
+                write!(f, "The checkpoint was already committed or rolled back")
+            }
+            Error::JournalOutOfStep { transfer_id } => write!(
+                f,
+                "The recorded change to transfer 0x{} does not fit the leaves, so the rollback stopped",
+                hex_encode(*transfer_id)
+            ),
+            Error::NotFound { transfer_id } => {
+                write!(f, "No transfer 0x{} in the tree", hex_encode(*transfer_id))
+            }
+            Error::ChannelMismatch { expected, got } => write!(
+                f,
+                "The tree is for channel {} but the transfer is in channel {}",
+                fmt_address(*expected),
+                fmt_address(*got)
+            ),
+            Error::InvalidDecimal => {
+                write!(f, "Expected a decimal integer of at most 256 bits")
+            }
+            Error::MultiproofNeedsSortedPairs => write!(
+                f,
+                "Multiproofs need a hasher that sorts pairs, as the verifier does not track positions"
+            ),
+            Error::MultiproofOrder { transfer_id } => write!(
+                f,
+                "Transfer 0x{} pairs with a node built from other transfers of the multiproof, \
+                 which the verifier cannot wait for, so it has to be proven separately",
+                hex_encode(*transfer_id)
+            ),
+            Error::NonInclusionNeedsUnsortedPairs => write!(
+                f,
+                "Non-inclusion proofs need a hasher that keeps the order of pairs, to show that the neighbors are adjacent"
+            ),
+            Error::TransferIncluded { transfer_id } => write!(
+                f,
+                "Transfer 0x{} is in the tree, so it has no non-inclusion proof",
+                hex_encode(*transfer_id)
+            ),
+            Error::StateNotRetained { transfer_id } => write!(
+                f,
+                "The state of transfer 0x{} was not kept, so the tree has to retain states before it is inserted",
+                hex_encode(*transfer_id)
+            ),
+            Error::NotBytes => write!(f, "Expected a Uint8Array"),
+            Error::NotString { index } => write!(f, "Expected a string at index {}", index),
+        }
+    }
+}
+
+/// Converts to a JS `Error` with the message, a `code` field and any details
+/// of the variant as further fields.
+impl From<Error> for JsValue {
+    fn from(error: Error) -> Self {
+        let js_error = js_sys::Error::new(&error.to_string());
+        let set = |key: &str, value: JsValue| {
+            let _ = js_sys::Reflect::set(&js_error, &JsValue::from_str(key), &value);
+        };
+        let hex = |value: &Bytes32| JsValue::from_str(&("0x".to_owned() + &hex_encode(*value)));
+        let address = |value: &Address| JsValue::from_str(&fmt_address(*value));
+
+        set("code", JsValue::from_str(error.code()));
+        match &error {
+            Error::WrongLength { expected, got } => {
+                set("expected", JsValue::from(*expected as u32));
+                set("got", JsValue::from(*got as u32));
+            }
+            Error::MissingPrefix => {}
+            Error::InvalidHexChar { index } => set("index", JsValue::from(*index as u32)),
+            Error::InvalidAddress { field } => set("field", JsValue::from_str(field)),
+            Error::DuplicateTransferID {
+                transfer_id,
+                existing,
+                inserted,
+            } => {
+                set("transferId", hex(transfer_id));
+                set("existingHash", hex(existing));
+                set("insertedHash", hex(inserted));
+            }
+            Error::InvalidSnapshotHeader => {}
+            Error::UnsupportedSnapshotVersion { version } => {
+                set("version", JsValue::from(*version))
+            }
+            Error::OutdatedSnapshotVersion { version, current } => {
+                set("version", JsValue::from(*version));
+                set("current", JsValue::from(*current));
+            }
+            Error::UnsortedLeaves { index } => set("index", JsValue::from(*index as u32)),
+            Error::InvalidSnapshotLeaf { index } => set("index", JsValue::from(*index as u32)),
+            Error::RootMismatch { expected, computed } => {
+                set("expected", hex(expected));
+                set("computed", hex(computed));
+            }
+            Error::ChecksumMismatch => {}
+            Error::Batch { errors } => {
+                let js_errors = js_sys::Array::new();
+                for (position, error) in errors {
+                    let item = JsValue::from(error.clone());
+                    let _ = js_sys::Reflect::set(
+                        &item,
+                        &JsValue::from_str("position"),
+                        &JsValue::from(*position as u32),
+                    );
+                    js_errors.push(&item);
+                }
+                set("errors", js_errors.into());
+            }
+            Error::UnknownCheckpoint => {}
+            Error::JournalOutOfStep { transfer_id } => set("transferId", hex(transfer_id)),
+            Error::NotFound { transfer_id } => set("transferId", hex(transfer_id)),
+            Error::ChannelMismatch { expected, got } => {
+                set("expected", address(expected));
+                set("got", address(got));
+            }
+            Error::InvalidDecimal => {}
+            Error::MultiproofNeedsSortedPairs => {}
+            Error::MultiproofOrder { transfer_id } => set("transferId", hex(transfer_id)),
+            Error::NonInclusionNeedsUnsortedPairs => {}
+            Error::TransferIncluded { transfer_id } => set("transferId", hex(transfer_id)),
+            Error::StateNotRetained { transfer_id } => set("transferId", hex(transfer_id)),
+            Error::NotBytes => {}
+            Error::NotString { index } => set("index", JsValue::from(*index as u32)),
         }
+        js_error.into()
     }
 }
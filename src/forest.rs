@@ -0,0 +1,182 @@
+use super::{
+    format, Address, Bytes32, CoreTransferState, Error, Keccak256, MerkleHasher, Proof, Tree,
+};
+use std::collections::BTreeMap;
+
+/// A tree for each channel, with transfers routed to the tree of the channel
+/// address in their state.
+///
+/// Channels without any transfers have no tree, and their root is the root
+/// of an empty tree.
+#[derive(Debug)]
+pub struct Forest<H = Keccak256> {
+    trees: BTreeMap<Address, Tree<H>>,
+}
+
+impl<H> Clone for Forest<H> {
+    fn clone(&self) -> Self {
+        Self {
+            trees: self.trees.clone(),
+        }
+    }
+}
+
+impl<H> Default for Forest<H> {
+    fn default() -> Self {
+        Self {
+            trees: BTreeMap::new(),
+        }
+    }
+}
+
+impl Forest {
+    /// Creates an empty forest hashed with Keccak-256.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<H: MerkleHasher> Forest<H> {
+    /// Insert a leaf with the given transfer state into the tree of its
+    /// channel.
+    pub fn insert_hex(&mut self, core_transfer_state: &str) -> Result<(), Error> {
+        let encoded = format::hex_to_encoded(core_transfer_state)?;
+        self.insert_encoded(&encoded)
+    }
+
+    /// Like `insert_hex`, with the ABI encoded transfer state, which must be
+    /// exactly 384 bytes.
+    pub fn insert_bytes(&mut self, core_transfer_state: &[u8]) -> Result<(), Error> {
+        self.insert_encoded(format::slice_to_encoded(core_transfer_state)?)
+    }
+
+    /// Like `insert_hex`, with the ABI encoded transfer state.
+    pub fn insert_encoded(&mut self, core_transfer_state: &[u8; 384]) -> Result<(), Error> {
+        let channel = CoreTransferState::decode_channel(core_transfer_state)?;
+        self.trees
+            .entry(channel)
+            .or_insert_with(|| Tree::with_channel(channel))
+            .insert_encoded(core_transfer_state)
+    }
+
+    /// Like `insert_hex`, with the decoded transfer state.
+    pub fn insert_state(&mut self, core_transfer_state: &CoreTransferState) -> Result<(), Error> {
+        let channel = core_transfer_state.channel_address;
+        self.trees
+            .entry(channel)
+            .or_insert_with(|| Tree::with_channel(channel))
+            .insert_state(core_transfer_state)
+    }
+
+    /// Remove the leaf of the transfer from the tree of the channel, returning
+    /// its hash or `None` if there was no such transfer.
+    pub fn delete_id(&mut self, channel: Address, transfer_id: Bytes32) -> Option<Bytes32> {
+        let tree = self.trees.get_mut(&channel)?;
+        let deleted = tree.delete_id(transfer_id);
+        if tree.is_empty() {
+            self.trees.remove(&channel);
+        }
+        deleted
+    }
+
+    /// The tree of the channel, if it has any transfers.
+    pub fn tree(&self, channel: Address) -> Option<&Tree<H>> {
+        self.trees.get(&channel)
+    }
+
+    pub fn root(&self, channel: Address) -> Bytes32 {
+        self.trees.get(&channel).map(Tree::root).unwrap_or_default()
+    }
+
+    /// Produces an inclusion proof for the transfer in the tree of the channel.
+    pub fn proof(&self, channel: Address, transfer_id: Bytes32) -> Option<Proof> {
+        self.trees.get(&channel)?.proof(transfer_id)
+    }
+
+    /// The root of every channel with transfers, in order of channel address.
+    pub fn roots(&self) -> impl Iterator<Item = (Address, Bytes32)> + '_ {
+        self.trees
+            .iter()
+            .map(|(channel, tree)| (*channel, tree.root()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::transfer_in_channel;
+    use crate::verify;
+
+    #[test]
+    fn routes_by_channel() {
+        let mut forest = Forest::new();
+        let mut trees = [Tree::new(), Tree::new(), Tree::new()];
+        for seed in 0..30 {
+            let state = transfer_in_channel((seed % 3) as u8, seed);
+            forest
+                .insert_hex(&format!(
+                    "0x{}",
+                    faster_hex::hex_string(&state.encode()).unwrap()
+                ))
+                .unwrap();
+            trees[state.channel_address[19] as usize]
+                .insert_state(&state)
+                .unwrap();
+        }
+
+        let roots: Vec<_> = forest.roots().collect();
+        assert_eq!(roots.len(), 3);
+        for (channel, root) in roots {
+            let tree = &trees[channel[19] as usize];
+            assert_eq!(root, tree.root());
+            assert_eq!(forest.root(channel), root);
+            assert_eq!(forest.tree(channel).unwrap().channel(), Some(channel));
+
+            for (transfer_id, hash) in tree.iter() {
+                let proof = forest.proof(channel, transfer_id).unwrap();
+                assert!(verify(root, hash, &proof));
+            }
+        }
+
+        // Transfers are only found in their own channel
+        let state = transfer_in_channel(1, 1);
+        assert!(forest
+            .proof(state.channel_address, state.transfer_id)
+            .is_some());
+        let other = transfer_in_channel(2, 1).channel_address;
+        assert_eq!(forest.proof(other, state.transfer_id), None);
+        assert_eq!(forest.delete_id(other, state.transfer_id), None);
+    }
+
+    #[test]
+    fn empty_channels_are_dropped() {
+        let mut forest = Forest::new();
+        let state = transfer_in_channel(7, 0);
+        forest.insert_bytes(&state.encode()).unwrap();
+        assert_ne!(forest.root(state.channel_address), Bytes32::default());
+
+        let hash = forest
+            .tree(state.channel_address)
+            .unwrap()
+            .get(state.transfer_id);
+        assert_eq!(
+            forest.delete_id(state.channel_address, state.transfer_id),
+            hash
+        );
+        assert!(forest.tree(state.channel_address).is_none());
+        assert_eq!(forest.roots().count(), 0);
+        assert_eq!(forest.root(state.channel_address), Bytes32::default());
+    }
+
+    #[test]
+    fn rejects_invalid_channel_address() {
+        let mut encoded = transfer_in_channel(1, 0).encode();
+        encoded[0] = 1;
+        assert_eq!(
+            Forest::new().insert_bytes(&encoded),
+            Err(Error::InvalidAddress {
+                field: "channelAddress"
+            })
+        );
+    }
+}
@@ -1,37 +1,205 @@
-use super::{hash, Bytes32, Error, Node};
+//! Conversions between hex strings and the binary values used by a tree.
+
+use super::{Address, Bytes32, Error, MerkleHasher, Node, U256};
 use faster_hex::hex_decode;
 use std::convert::TryInto;
 
 /// Takes an encoded transfer state and produces a leaf node
+#[cfg(test)]
 pub(crate) fn hex_to_node(core_transfer_state: &str) -> Result<Node, Error> {
-    if core_transfer_state.len() != 770 || &core_transfer_state[..2] != "0x" {
-        return Err(Error::InvalidFormat);
-    }
+    let core_transfer_state_binary = hex_to_encoded(core_transfer_state)?;
+    Ok(encoded_to_node::<super::Keccak256>(&core_transfer_state_binary))
+}
+
+/// Takes a 0x-hex transfer state and produces its ABI encoding
+pub fn hex_to_encoded(core_transfer_state: &str) -> Result<[u8; 384], Error> {
     let mut core_transfer_state_binary = [0u8; 384];
-    hex_decode(
-        &core_transfer_state[2..].as_bytes(),
-        &mut core_transfer_state_binary,
-    )
-    .map_err(|_| Error::InvalidFormat)?;
-
-    let hash = hash::keccak(&core_transfer_state_binary);
-    let transfer_id = core_transfer_state_binary[32..64].try_into().unwrap();
-    let node = Node { hash, transfer_id };
-    Ok(node)
+    decode_prefixed(core_transfer_state, &mut core_transfer_state_binary)?;
+    Ok(core_transfer_state_binary)
+}
+
+/// Decodes a 0x-hex string that must fill the output exactly
+fn decode_prefixed(value: &str, out: &mut [u8]) -> Result<(), Error> {
+    let expected = 2 + out.len() * 2;
+    if value.len() != expected {
+        return Err(Error::WrongLength {
+            expected,
+            got: value.len(),
+        });
+    }
+    if !value.starts_with("0x") {
+        return Err(Error::MissingPrefix);
+    }
+    let digits = &value.as_bytes()[2..];
+    if let Some(i) = digits.iter().position(|c| !c.is_ascii_hexdigit()) {
+        return Err(Error::InvalidHexChar { index: i + 2 });
+    }
+    // Can't fail after the checks above
+    hex_decode(digits, out).unwrap();
+    Ok(())
+}
+
+/// Checks that a slice is exactly the ABI encoding of a transfer state
+pub(crate) fn slice_to_encoded(core_transfer_state: &[u8]) -> Result<&[u8; 384], Error> {
+    core_transfer_state
+        .try_into()
+        .map_err(|_| Error::WrongLength {
+            expected: 384,
+            got: core_transfer_state.len(),
+        })
+}
+
+/// Takes the ABI encoding of a transfer state and produces a leaf node
+pub(crate) fn encoded_to_node<H: MerkleHasher>(core_transfer_state: &[u8; 384]) -> Node {
+    Node {
+        hash: H::hash_leaf(core_transfer_state),
+        transfer_id: core_transfer_state[32..64].try_into().unwrap(),
+    }
+}
+
+/// The fields of a transfer state that a tree keeps for every leaf, for its
+/// channel binding, balances, indexes and timeouts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Fields {
+    channel_address: Address,
+    transfer_definition: Address,
+    initiator: Address,
+    responder: Address,
+    asset_id: Address,
+    amount: [U256; 2],
+    transfer_timeout: U256,
+}
+
+impl Fields {
+    /// The length of `encode`, five addresses and three integers.
+    pub const LEN: usize = 5 * 20 + 3 * 32;
+
+    /// Reads the fields of an ABI encoded transfer state, at the word offsets
+    /// of `CoreTransferState`. Address padding is not checked.
+    pub fn read(core_transfer_state: &[u8; 384]) -> Self {
+        let word = |i: usize| &core_transfer_state[i * 32..(i + 1) * 32];
+        let address = |i: usize| word(i)[12..].try_into().unwrap();
+        let uint = |i: usize| U256::from_be_bytes(word(i).try_into().unwrap());
+        Fields {
+            channel_address: address(0),
+            transfer_definition: address(2),
+            initiator: address(3),
+            responder: address(4),
+            asset_id: address(5),
+            amount: [uint(6), uint(7)],
+            transfer_timeout: uint(10),
+        }
+    }
+
+    /// Packs the fields without padding, for snapshots.
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        let mut encoded = [0u8; Self::LEN];
+        let addresses = [
+            self.channel_address,
+            self.transfer_definition,
+            self.initiator,
+            self.responder,
+            self.asset_id,
+        ];
+        let uints = [self.amount[0], self.amount[1], self.transfer_timeout];
+        let (address_bytes, uint_bytes) = encoded.split_at_mut(5 * 20);
+        for (out, address) in address_bytes.chunks_exact_mut(20).zip(&addresses) {
+            out.copy_from_slice(address);
+        }
+        for (out, uint) in uint_bytes.chunks_exact_mut(32).zip(&uints) {
+            out.copy_from_slice(&uint.to_be_bytes());
+        }
+        encoded
+    }
+
+    /// The inverse of `encode`.
+    pub fn decode(encoded: &[u8; Self::LEN]) -> Self {
+        let address = |i: usize| encoded[i * 20..(i + 1) * 20].try_into().unwrap();
+        let uint = |i: usize| {
+            let start = 5 * 20 + i * 32;
+            U256::from_be_bytes(encoded[start..start + 32].try_into().unwrap())
+        };
+        Fields {
+            channel_address: address(0),
+            transfer_definition: address(1),
+            initiator: address(2),
+            responder: address(3),
+            asset_id: address(4),
+            amount: [uint(0), uint(1)],
+            transfer_timeout: uint(2),
+        }
+    }
+
+    pub fn channel_address(&self) -> Address {
+        self.channel_address
+    }
+
+    pub fn transfer_definition(&self) -> Address {
+        self.transfer_definition
+    }
+
+    pub fn initiator(&self) -> Address {
+        self.initiator
+    }
+
+    pub fn responder(&self) -> Address {
+        self.responder
+    }
+
+    pub fn asset_id(&self) -> Address {
+        self.asset_id
+    }
+
+    pub fn amount(&self) -> [U256; 2] {
+        self.amount
+    }
+
+    pub fn transfer_timeout(&self) -> U256 {
+        self.transfer_timeout
+    }
 }
 
-pub(crate) fn hex_encode(data: Bytes32) -> String {
+/// Encodes a 32 byte value as hex, without a 0x prefix
+pub fn hex_encode(data: Bytes32) -> String {
     let mut s = "0".repeat(64);
-    faster_hex::hex_encode(&data, unsafe { &mut s.as_bytes_mut() }).unwrap();
+    faster_hex::hex_encode(&data, unsafe { s.as_bytes_mut() }).unwrap();
     s
 }
 
+/// Encodes an address as a 0x-prefixed hex string
+pub fn fmt_address(address: Address) -> String {
+    "0x".to_owned() + &faster_hex::hex_string(&address).unwrap()
+}
+
+/// Parses a 0x-prefixed 32 byte value, such as a transfer id
+pub fn hex_to_bytes32(value: &str) -> Result<Bytes32, Error> {
+    let mut bytes = Bytes32::default();
+    decode_prefixed(value, &mut bytes)?;
+    Ok(bytes)
+}
+
+/// Parses a 0x-prefixed 20 byte address
+pub fn hex_to_address(value: &str) -> Result<Address, Error> {
+    let mut bytes = Address::default();
+    decode_prefixed(value, &mut bytes)?;
+    Ok(bytes)
+}
+
+/// Parses an unsigned decimal integer of up to 256 bits, such as a timestamp
+pub fn decimal_to_u256(value: &str) -> Result<U256, Error> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(Error::InvalidDecimal);
+    }
+    U256::from_str_radix(value, 10).map_err(|_| Error::InvalidDecimal)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     /// Verifies that the merkle leaves contain the same data as the TypeScript implementation.
     #[test]
+    #[allow(clippy::needless_borrow)]
     fn node_same_as_before() {
         let state = "0x000000000000000000000000ccc0000000000000000000000000000000000000364b4e94b854e94d5f35bf42698696b3064c91aaef39831bfd6c296aa6d1c33f000000000000000000000000def0000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001abcdef0000000000000000000000000000000000000000000000000000000000";
         let Node { transfer_id, hash } = hex_to_node(&state).unwrap();
@@ -54,14 +222,45 @@ mod tests {
     fn errors() {
         // Invalid hex characters
         let state = "0xNOTHEX000000000000000000ccc0000000000000000000000000000000000000364b4e94b854e94d5f35bf42698696b3064c91aaef39831bfd6c296aa6d1c33f000000000000000000000000def0000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001abcdef0000000000000000000000000000000000000000000000000000000000";
-        assert_eq!(Err(Error::InvalidFormat), hex_to_node(state));
+        assert_eq!(
+            Err(Error::InvalidHexChar { index: 2 }),
+            hex_to_node(state)
+        );
 
         // Too many characters
         let state = "0x000000000000000000000000ccc0000000000000000000000000000000000000364b4e94b854e94d5f35bf42698696b3064c91aaef39831bfd6c296aa6d1c33f000000000000000000000000def0000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001abcdef000000000000000000000000000000000000000000000000000000000000";
-        assert_eq!(Err(Error::InvalidFormat), hex_to_node(state));
+        assert_eq!(
+            Err(Error::WrongLength {
+                expected: 770,
+                got: 772
+            }),
+            hex_to_node(state)
+        );
 
         // Missing 0x
         let state = "000000000000000000000000ccc0000000000000000000000000000000000000364b4e94b854e94d5f35bf42698696b3064c91aaef39831bfd6c296aa6d1c33f000000000000000000000000def0000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001abcdef000000000000000000000000000000000000000000000000000000000000";
-        assert_eq!(Err(Error::InvalidFormat), hex_to_node(state));
+        assert_eq!(Err(Error::MissingPrefix), hex_to_node(state));
+
+        // Decimals
+        assert_eq!(decimal_to_u256("1000"), Ok(U256::from(1000u32)));
+        assert_eq!(decimal_to_u256(&U256::MAX.to_string()), Ok(U256::MAX));
+        for value in ["", "-1", "+1", "1e3", "0x10", " 1"] {
+            assert_eq!(decimal_to_u256(value), Err(Error::InvalidDecimal));
+        }
+        let too_big = U256::MAX.to_string() + "0";
+        assert_eq!(decimal_to_u256(&too_big), Err(Error::InvalidDecimal));
+
+        // Transfer ids
+        assert_eq!(
+            Err(Error::WrongLength {
+                expected: 66,
+                got: 2
+            }),
+            hex_to_bytes32("0x")
+        );
+        assert_eq!(
+            Err(Error::InvalidHexChar { index: 65 }),
+            hex_to_bytes32("0x000000000000000000000000000000000000000000000000000000000000000g")
+        );
     }
 }
@@ -1,6 +1,100 @@
 use super::Bytes32;
+use std::marker::PhantomData;
 use tiny_keccak::{Hasher, Keccak};
 
+/// The hash functions that make up a tree.
+///
+/// Implementations only need to provide hashing of leaves and of an ordered
+/// pair of nodes. The tree itself combines nodes with `combine`, which by
+/// default sorts the pair first so that proofs need no left/right information.
+pub trait MerkleHasher {
+    /// Whether `combine` sorts the pair before hashing. When it does not, a
+    /// proof only verifies with the directions it was produced with, which
+    /// binds the leaf to its index.
+    const SORTED_PAIRS: bool = true;
+
+    /// Hashes the ABI encoded core transfer state of a leaf.
+    fn hash_leaf(data: &[u8]) -> Bytes32;
+
+    /// Hashes two nodes in the order given.
+    fn hash_pair(left: &Bytes32, right: &Bytes32) -> Bytes32;
+
+    /// Hashes a left and right sibling into their parent, smallest first
+    /// unless `SORTED_PAIRS` is turned off.
+    fn combine(left: &Bytes32, right: &Bytes32) -> Bytes32 {
+        if Self::SORTED_PAIRS && right < left {
+            Self::hash_pair(right, left)
+        } else {
+            Self::hash_pair(left, right)
+        }
+    }
+}
+
+/// Keccak-256, as used by the Vector contracts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Keccak256;
+
+impl MerkleHasher for Keccak256 {
+    fn hash_leaf(data: &[u8]) -> Bytes32 {
+        keccak(data)
+    }
+
+    fn hash_pair(left: &Bytes32, right: &Bytes32) -> Bytes32 {
+        let mut keccak256 = Keccak::v256();
+        keccak256.update(left);
+        keccak256.update(right);
+        let mut into = [0; 32];
+        keccak256.finalize(&mut into);
+        into
+    }
+}
+
+/// Hashes leaves twice with `H`, which keeps a leaf from ever being mistaken
+/// for an internal node: both are 32 bytes, and without this the two children
+/// of an internal node could be passed off as the encoding of a leaf.
+///
+/// With the default Keccak-256 a leaf is `keccak(keccak(abi.encode(state)))`,
+/// matching OpenZeppelin's `StandardMerkleTree`. Internal nodes are unchanged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DoubleHashed<H = Keccak256>(PhantomData<H>);
+
+impl<H: MerkleHasher> MerkleHasher for DoubleHashed<H> {
+    const SORTED_PAIRS: bool = H::SORTED_PAIRS;
+
+    fn hash_leaf(data: &[u8]) -> Bytes32 {
+        H::hash_leaf(&H::hash_leaf(data))
+    }
+
+    fn hash_pair(left: &Bytes32, right: &Bytes32) -> Bytes32 {
+        H::hash_pair(left, right)
+    }
+
+    fn combine(left: &Bytes32, right: &Bytes32) -> Bytes32 {
+        H::combine(left, right)
+    }
+}
+
+/// Hashes each pair with `H` in tree order, left then right, instead of
+/// sorting it. Proofs then carry the side of each sibling and verify only at
+/// the index they were produced for.
+///
+/// Such trees cannot produce multiproofs, since OpenZeppelin's verifier
+/// always sorts pairs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Positional<H = Keccak256>(PhantomData<H>);
+
+impl<H: MerkleHasher> MerkleHasher for Positional<H> {
+    const SORTED_PAIRS: bool = false;
+
+    fn hash_leaf(data: &[u8]) -> Bytes32 {
+        H::hash_leaf(data)
+    }
+
+    fn hash_pair(left: &Bytes32, right: &Bytes32) -> Bytes32 {
+        H::hash_pair(left, right)
+    }
+}
+
 pub fn keccak(data: &[u8]) -> Bytes32 {
     let mut hash = [0; 32];
     let mut hasher = Keccak::v256();
@@ -8,14 +102,3 @@ pub fn keccak(data: &[u8]) -> Bytes32 {
     hasher.finalize(&mut hash);
     hash
 }
-
-pub fn combine(a: &Bytes32, b: &Bytes32) -> Bytes32 {
-    let (first, second) = if a < b { (a, b) } else { (b, a) };
-
-    let mut keccak256 = Keccak::v256();
-    keccak256.update(first);
-    keccak256.update(second);
-    let mut into = [0; 32];
-    keccak256.finalize(&mut into);
-    into
-}
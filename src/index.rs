@@ -0,0 +1,207 @@
+//! Optional indexes from the addresses in transfer states to the transfers
+//! that have them, for listing a participant's transfers or those of one
+//! kind without going through every leaf.
+
+use super::{format::Fields, Address, Bytes32, LeafData, Tree};
+use std::collections::BTreeSet;
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Indexes {
+    enabled: bool,
+    initiator: BTreeSet<(Address, Bytes32)>,
+    responder: BTreeSet<(Address, Bytes32)>,
+    transfer_definition: BTreeSet<(Address, Bytes32)>,
+}
+
+impl Indexes {
+    pub fn add(&mut self, id: Bytes32, state: &Fields) {
+        if self.enabled {
+            self.initiator.insert((state.initiator(), id));
+            self.responder.insert((state.responder(), id));
+            self.transfer_definition
+                .insert((state.transfer_definition(), id));
+        }
+    }
+
+    pub fn remove(&mut self, id: Bytes32, state: &Fields) {
+        if self.enabled {
+            self.initiator.remove(&(state.initiator(), id));
+            self.responder.remove(&(state.responder(), id));
+            self.transfer_definition
+                .remove(&(state.transfer_definition(), id));
+        }
+    }
+}
+
+/// The transfer ids indexed under the address, in order.
+fn lookup(
+    index: &BTreeSet<(Address, Bytes32)>,
+    address: Address,
+) -> impl Iterator<Item = Bytes32> + '_ {
+    index
+        .range((address, [0; 32])..=(address, [0xff; 32]))
+        .map(|&(_, transfer_id)| transfer_id)
+}
+
+impl<H> Tree<H> {
+    /// Builds indexes over the initiator, responder and transfer definition
+    /// of every transfer, which are then kept up to date as leaves are
+    /// inserted and deleted. Without them the `transfers_by_*` queries scan
+    /// every leaf.
+    pub fn enable_indexes(&mut self) {
+        let LeafData { kept, indexes, .. } = &mut self.leaf_data;
+        if !indexes.enabled {
+            indexes.enabled = true;
+            for leaf in self.leaves.iter() {
+                indexes.add(leaf.transfer_id, &kept[&leaf.transfer_id].fields);
+            }
+        }
+    }
+
+    /// Drops the indexes built by `enable_indexes`.
+    pub fn disable_indexes(&mut self) {
+        self.leaf_data.indexes = Indexes::default();
+    }
+
+    pub fn has_indexes(&self) -> bool {
+        self.leaf_data.indexes.enabled
+    }
+
+    /// The transfers in the tree with the given initiator, in order of
+    /// transfer id.
+    pub fn transfers_by_initiator(
+        &self,
+        initiator: Address,
+    ) -> Box<dyn Iterator<Item = Bytes32> + '_> {
+        self.transfers_by(
+            &self.leaf_data.indexes.initiator,
+            initiator,
+            |state| state.initiator(),
+        )
+    }
+
+    /// Like `transfers_by_initiator`, for the responder.
+    pub fn transfers_by_responder(
+        &self,
+        responder: Address,
+    ) -> Box<dyn Iterator<Item = Bytes32> + '_> {
+        self.transfers_by(
+            &self.leaf_data.indexes.responder,
+            responder,
+            |state| state.responder(),
+        )
+    }
+
+    /// Like `transfers_by_initiator`, for the transfer definition, which is
+    /// the contract of the kind of transfer.
+    pub fn transfers_by_definition(
+        &self,
+        transfer_definition: Address,
+    ) -> Box<dyn Iterator<Item = Bytes32> + '_> {
+        self.transfers_by(
+            &self.leaf_data.indexes.transfer_definition,
+            transfer_definition,
+            |state| state.transfer_definition(),
+        )
+    }
+
+    /// Looks the address up in the index if there is one, and otherwise
+    /// compares it with the field of every leaf.
+    fn transfers_by<'a>(
+        &'a self,
+        index: &'a BTreeSet<(Address, Bytes32)>,
+        address: Address,
+        field: fn(&Fields) -> Address,
+    ) -> Box<dyn Iterator<Item = Bytes32> + 'a> {
+        if self.leaf_data.indexes.enabled {
+            Box::new(lookup(index, address))
+        } else {
+            Box::new(
+                self.leaves
+                    .iter()
+                    .filter(move |n| field(&self.leaf_data.kept[&n.transfer_id].fields) == address)
+                    .map(|n| n.transfer_id),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::transfer_with_addresses;
+
+    /// Checks that every query gives the same ids with and without indexes.
+    fn check(tree: &Tree) {
+        let mut indexed = tree.clone();
+        indexed.enable_indexes();
+        let mut scanned = tree.clone();
+        scanned.disable_indexes();
+        for address in (0..16).map(|b| [b; 20]) {
+            let by = |tree: &Tree| {
+                (
+                    tree.transfers_by_initiator(address).collect::<Vec<_>>(),
+                    tree.transfers_by_responder(address).collect::<Vec<_>>(),
+                    tree.transfers_by_definition(address).collect::<Vec<_>>(),
+                )
+            };
+            assert_eq!(by(tree), by(&scanned));
+            assert_eq!(by(&indexed), by(&scanned));
+        }
+    }
+
+    #[test]
+    fn indexes_match_a_scan() {
+        let states: Vec<_> = (0..30).map(transfer_with_addresses).collect();
+        let mut tree = Tree::new();
+        tree.enable_indexes();
+        for state in &states[..10] {
+            tree.insert_state(state).unwrap();
+        }
+        let encoded: Vec<_> = states[10..20].iter().map(|s| s.encode()).collect();
+        tree.insert_many_bytes(&encoded).unwrap();
+        check(&tree);
+
+        let checkpoint = tree.checkpoint();
+        let mut changed = states[2].clone();
+        changed.initiator = [7; 20];
+        tree.upsert_state(&changed).unwrap();
+        tree.delete_id(states[3].transfer_id);
+        tree.delete_many(states[12..16].iter().map(|s| s.transfer_id));
+        check(&tree);
+        assert_eq!(
+            tree.transfers_by_initiator([7; 20]).collect::<Vec<_>>(),
+            vec![changed.transfer_id]
+        );
+
+        tree.rollback(checkpoint).unwrap();
+        check(&tree);
+        assert_eq!(tree.transfers_by_initiator([7; 20]).count(), 0);
+
+        // Indexes are not kept in snapshots
+        let restored: Tree = Tree::from_bytes(&tree.to_bytes()).unwrap();
+        assert!(!restored.has_indexes());
+        check(&restored);
+    }
+
+    #[test]
+    fn finds_matching_transfers() {
+        let mut tree = Tree::new();
+        tree.enable_indexes();
+        let states: Vec<_> = (0..12).map(transfer_with_addresses).collect();
+        for state in &states {
+            tree.insert_state(state).unwrap();
+        }
+
+        let mut expected: Vec<_> = states
+            .iter()
+            .filter(|s| s.transfer_definition == [11; 20])
+            .map(|s| s.transfer_id)
+            .collect();
+        expected.sort_unstable();
+        assert_eq!(expected.len(), 3);
+        assert!(tree.transfers_by_definition([11; 20]).eq(expected));
+        assert_eq!(tree.transfers_by_responder([3; 20]).count(), 6);
+        assert_eq!(tree.transfers_by_initiator([3; 20]).count(), 0);
+    }
+}
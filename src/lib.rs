@@ -1,28 +1,46 @@
-use std::cell::RefCell;
-
-use faster_hex::hex_decode;
-use format::hex_encode;
-use wasm_bindgen::prelude::*;
-
-thread_local! {
-    // TODO: (Performance)
-    // This scratch is way too big. If using depth-first traversal
-    // only 2 * log2(n) values would be needed at any time, instead of
-    // the n values we are using now. (Justifies just using the stack
-    // and recursing, even)
-    static SCRATCH: RefCell<Vec<Bytes32>> = RefCell::new(Vec::new());
-}
+use std::borrow::Borrow;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::marker::PhantomData;
+use std::sync::Mutex;
+
+use balances::Locked;
+use cache::Cache;
+use checkpoint::{Change, Journal};
+use format::Fields;
+use index::Indexes;
 
 type Bytes32 = [u8; 32];
 
+mod balances;
+mod cache;
+mod checkpoint;
+mod diff;
 mod error;
+mod forest;
 mod format;
 mod hash;
+mod index;
+mod proof;
+mod snapshot;
+mod state;
+mod wasm;
 
 #[cfg(test)]
 mod test_utils;
 
+pub use checkpoint::Checkpoint;
+pub use diff::{Diff, IdRange, RangeContents, RangeSummary};
 pub use error::Error;
+pub use ethnum::U256;
+pub use forest::Forest;
+pub use format::hex_to_bytes32;
+pub use hash::{DoubleHashed, Keccak256, MerkleHasher, Positional};
+pub use proof::{
+    verify, verify_multiproof, verify_multiproof_with, verify_non_inclusion_with, verify_with,
+    MultiProof, Neighbor, NonInclusionProof, Proof,
+};
+pub use state::{Address, Balance, CoreTransferState};
+pub use wasm::{JsForest, JsTree};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub(crate) struct Node {
@@ -30,48 +48,204 @@ pub(crate) struct Node {
     transfer_id: Bytes32,
 }
 
-#[wasm_bindgen]
-#[derive(Debug, Clone)]
-pub struct Tree {
+/// What a tree keeps about one leaf besides its hash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Kept {
+    fields: Fields,
+    /// The ABI encoded transfer state, if it was inserted while the tree
+    /// retained states.
+    state: Option<Box<[u8; 384]>>,
+}
+
+/// What a tree keeps about its leaves besides their order and hashes, all of
+/// it derived from their states. Every leaf added to or removed from
+/// `Tree::leaves` goes through these hooks.
+#[derive(Debug, Clone, Default)]
+struct LeafData {
+    /// What is kept about each leaf, by transfer id.
+    kept: HashMap<Bytes32, Kept>,
+    /// Whether the states of leaves added from now on are kept.
+    retain_states: bool,
+    /// Every leaf by timeout then transfer id, soonest first.
+    timeouts: BTreeSet<(U256, Bytes32)>,
+    locked: Locked,
+    indexes: Indexes,
+}
+
+impl LeafData {
+    /// What to keep about a leaf with the state.
+    fn keep(&self, state: &[u8; 384]) -> Kept {
+        Kept {
+            fields: Fields::read(state),
+            state: self.retain_states.then(|| Box::new(*state)),
+        }
+    }
+
+    fn on_added(&mut self, node: &Node, mut kept: Kept) {
+        let fields = &kept.fields;
+        self.timeouts.insert((fields.transfer_timeout(), node.transfer_id));
+        self.locked.add(fields);
+        self.indexes.add(node.transfer_id, fields);
+        // A leaf restored by a rollback may have been removed while states
+        // were still retained
+        if !self.retain_states {
+            kept.state = None;
+        }
+        self.kept.insert(node.transfer_id, kept);
+    }
+
+    /// Returns what was kept about the removed leaf.
+    fn on_removed(&mut self, node: &Node) -> Kept {
+        let kept = self
+            .kept
+            .remove(&node.transfer_id)
+            .expect("every leaf is kept");
+        let fields = &kept.fields;
+        self.timeouts.remove(&(fields.transfer_timeout(), node.transfer_id));
+        self.locked.remove(fields);
+        self.indexes.remove(node.transfer_id, fields);
+        kept
+    }
+}
+
+/// A merkle tree over the active transfers of a channel, with leaves sorted by
+/// transfer id.
+///
+/// The hash functions are chosen by `H`, which defaults to the Keccak-256 used
+/// on chain. Trees with other hashers are created with `Tree::default()`, for
+/// example `Tree::<DoubleHashed>::default()` to hash leaves twice or
+/// `Tree::<Positional>::default()` for proofs that bind leaves to indices.
+///
+/// A tree can be bound to a channel, after which inserting the state of a
+/// transfer in any other channel fails with `Error::ChannelMismatch`.
+#[derive(Debug)]
+pub struct Tree<H = Keccak256> {
     leaves: Vec<Node>,
+    cache: Mutex<Cache>,
+    journal: Journal,
+    channel: Binding,
+    leaf_data: LeafData,
+    hasher: PhantomData<fn() -> H>,
+}
+
+/// Which channel the states inserted into a tree must belong to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Binding {
+    Unbound,
+    /// Bound to the channel of the first state inserted.
+    FirstInsert,
+    Channel(Address),
+}
+
+impl Binding {
+    /// Checks the channel of an encoded state, binding to it if this is the
+    /// first insert.
+    fn check(&mut self, core_transfer_state: &[u8; 384]) -> Result<(), Error> {
+        match *self {
+            Binding::Unbound => Ok(()),
+            _ => self.check_channel(CoreTransferState::decode_channel(core_transfer_state)?),
+        }
+    }
+
+    /// Like `check`, with the channel already decoded.
+    fn check_channel(&mut self, got: Address) -> Result<(), Error> {
+        match *self {
+            Binding::Unbound => {}
+            Binding::FirstInsert => *self = Binding::Channel(got),
+            Binding::Channel(expected) => {
+                if got != expected {
+                    return Err(Error::ChannelMismatch { expected, got });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<H> Clone for Tree<H> {
+    fn clone(&self) -> Self {
+        Self {
+            leaves: self.leaves.clone(),
+            cache: Mutex::new(self.cache.lock().unwrap().clone()),
+            journal: Journal::default(),
+            channel: self.channel,
+            leaf_data: self.leaf_data.clone(),
+            hasher: PhantomData,
+        }
+    }
+}
+
+impl<H> Default for Tree<H> {
+    fn default() -> Self {
+        Self {
+            leaves: Vec::new(),
+            cache: Default::default(),
+            journal: Default::default(),
+            channel: Binding::Unbound,
+            leaf_data: Default::default(),
+            hasher: PhantomData,
+        }
+    }
 }
 
-#[wasm_bindgen]
 impl Tree {
-    #[wasm_bindgen(constructor)]
+    /// Creates an empty tree hashed with Keccak-256.
     pub fn new() -> Self {
-        Self { leaves: Vec::new() }
+        Self::default()
     }
+}
 
-    #[wasm_bindgen(js_name = insertHex)]
-    pub fn insert_hex_js(&mut self, core_transfer_state: &str) -> Result<(), JsValue> {
-        self.insert_hex(core_transfer_state)
-            .map_err(|e| JsValue::from_str(&format!("{}", e)))
+impl<H> Tree<H> {
+    /// Creates an empty tree that only accepts transfers in the channel.
+    pub fn with_channel(channel: Address) -> Self {
+        Self {
+            channel: Binding::Channel(channel),
+            ..Self::default()
+        }
     }
 
-    #[wasm_bindgen(js_name = deleteId)]
-    pub fn delete_id_js(&mut self, transfer_id: &str) -> Result<(), JsValue> {
-        if transfer_id.len() != 66 || &transfer_id[..2] != "0x" {
-            return Err(JsValue::from_str("Invalid transfer id"));
+    /// Creates an empty tree that is bound to the channel of the first
+    /// transfer inserted.
+    pub fn bound_on_first_insert() -> Self {
+        Self {
+            channel: Binding::FirstInsert,
+            ..Self::default()
         }
-        let mut bytes = Bytes32::default();
-        hex_decode(transfer_id[2..].as_bytes(), &mut bytes)
-            .map_err(|_| JsValue::from_str("Invalid transfer id"))?;
+    }
 
-        self.delete_id(bytes);
-        Ok(())
+    /// The channel the tree is bound to, if any. A tree bound on first insert
+    /// stays bound even once that transfer is deleted or rolled back.
+    pub fn channel(&self) -> Option<Address> {
+        match self.channel {
+            Binding::Channel(channel) => Some(channel),
+            _ => None,
+        }
+    }
+
+    /// Keeps the ABI encoded state of every transfer inserted from now on,
+    /// which `non_inclusion_proof` needs for the neighbors of an absent id.
+    /// Otherwise only the fields behind the channel binding, balances,
+    /// indexes and timeouts are kept, so the states of transfers already in
+    /// the tree are not known.
+    pub fn retain_states(&mut self) {
+        self.leaf_data.retain_states = true;
     }
 
-    #[wasm_bindgen(js_name = root)]
-    pub fn root_js(&self) -> JsValue {
-        let root = self.root();
-        let s = "0x".to_owned() + &hex_encode(root);
-        JsValue::from_str(&s)
+    /// Drops the states kept since `retain_states`, and stops keeping them.
+    pub fn discard_states(&mut self) {
+        self.leaf_data.retain_states = false;
+        for kept in self.leaf_data.kept.values_mut() {
+            kept.state = None;
+        }
+    }
+
+    pub fn retains_states(&self) -> bool {
+        self.leaf_data.retain_states
     }
 }
 
-impl Tree {
-    fn insert_node(&mut self, node: Node) -> Result<(), Error> {
+impl<H: MerkleHasher> Tree<H> {
+    fn insert_node(&mut self, node: Node, state: &[u8; 384]) -> Result<(), Error> {
         match self
             .leaves
             .binary_search_by_key(&&node.transfer_id, |n| &n.transfer_id)
@@ -81,82 +255,527 @@ impl Tree {
             // exists it can treat this idempotently.
             Ok(i) => {
                 if node.hash != self.leaves[i].hash {
-                    return Err(Error::DuplicateTransferID);
+                    return Err(Error::DuplicateTransferID {
+                        transfer_id: node.transfer_id,
+                        existing: self.leaves[i].hash,
+                        inserted: node.hash,
+                    });
                 }
             }
             Err(i) => {
                 self.leaves.insert(i, node);
+                self.cache.get_mut().unwrap().invalidate(i);
+                self.leaf_data.on_added(&node, self.leaf_data.keep(state));
+                self.journal.record(Change::Inserted(node.transfer_id));
             }
         };
         Ok(())
     }
 
     /// Insert a leaf with the given transfer state.
+    ///
+    /// Fails with `Error::ChannelMismatch` if the tree is bound to a different
+    /// channel than the one in the state.
     pub fn insert_hex(&mut self, core_transfer_state: &str) -> Result<(), Error> {
-        let node = format::hex_to_node(core_transfer_state)?;
-        self.insert_node(node)
+        self.insert_encoded(&format::hex_to_encoded(core_transfer_state)?)
+    }
+
+    /// Insert a leaf with the given ABI encoded transfer state, which must be
+    /// exactly 384 bytes.
+    pub fn insert_bytes(&mut self, core_transfer_state: &[u8]) -> Result<(), Error> {
+        self.insert_encoded(format::slice_to_encoded(core_transfer_state)?)
+    }
+
+    /// Insert a leaf with the given ABI encoded transfer state.
+    pub fn insert_encoded(&mut self, core_transfer_state: &[u8; 384]) -> Result<(), Error> {
+        let mut channel = self.channel;
+        channel.check(core_transfer_state)?;
+        let node = format::encoded_to_node::<H>(core_transfer_state);
+        self.insert_node(node, core_transfer_state)?;
+        self.channel = channel;
+        Ok(())
+    }
+
+    /// Insert a leaf with the given decoded transfer state.
+    pub fn insert_state(&mut self, core_transfer_state: &CoreTransferState) -> Result<(), Error> {
+        self.insert_encoded(&core_transfer_state.encode())
     }
 
-    /// Remove the leaf corresponding to the transfer with a given id.
-    pub fn delete_id(&mut self, transfer_id: Bytes32) {
-        if let Ok(i) = self
+    /// Merges a batch of states into the leaves in one pass. Nothing is
+    /// inserted unless every state can be.
+    fn insert_nodes<S: Borrow<[u8; 384]>>(
+        &mut self,
+        states: impl Iterator<Item = Result<S, Error>>,
+    ) -> Result<(), Error> {
+        let mut channel = self.channel;
+        let mut errors = Vec::new();
+        let mut batch = Vec::new();
+        for (position, state) in states.enumerate() {
+            let node = state.and_then(|state| {
+                channel.check(state.borrow())?;
+                Ok((format::encoded_to_node::<H>(state.borrow()), state))
+            });
+            match node {
+                Ok((node, state)) => batch.push((position, node, state)),
+                Err(error) => errors.push((position, error)),
+            }
+        }
+
+        // The sort is stable, so the first state given for a transfer is the
+        // one that later states in the batch conflict with.
+        batch.sort_by_key(|(_, node, _)| node.transfer_id);
+        let mut unique: Vec<(usize, Node, S)> = Vec::with_capacity(batch.len());
+        for (position, node, state) in batch {
+            match unique.last() {
+                Some((_, first, _)) if first.transfer_id == node.transfer_id => {
+                    if first.hash != node.hash {
+                        errors.push((
+                            position,
+                            Error::DuplicateTransferID {
+                                transfer_id: node.transfer_id,
+                                existing: first.hash,
+                                inserted: node.hash,
+                            },
+                        ));
+                    }
+                }
+                _ => unique.push((position, node, state)),
+            }
+        }
+
+        let mut merged = Vec::with_capacity(self.leaves.len() + unique.len());
+        let mut first_inserted = None;
+        let mut inserted = Vec::new();
+        let mut leaves = self.leaves.iter().copied().peekable();
+        for (position, node, state) in unique {
+            while let Some(leaf) = leaves.next_if(|leaf| leaf.transfer_id < node.transfer_id) {
+                merged.push(leaf);
+            }
+            match leaves.peek() {
+                // Already in the tree, and left to be copied over
+                Some(leaf) if leaf.transfer_id == node.transfer_id => {
+                    if leaf.hash != node.hash {
+                        errors.push((
+                            position,
+                            Error::DuplicateTransferID {
+                                transfer_id: node.transfer_id,
+                                existing: leaf.hash,
+                                inserted: node.hash,
+                            },
+                        ));
+                    }
+                }
+                _ => {
+                    first_inserted.get_or_insert(merged.len());
+                    merged.push(node);
+                    inserted.push((node, state));
+                }
+            }
+        }
+        merged.extend(leaves);
+
+        if !errors.is_empty() {
+            errors.sort_by_key(|(position, _)| *position);
+            return Err(Error::Batch { errors });
+        }
+        if let Some(i) = first_inserted {
+            self.leaves = merged;
+            self.cache.get_mut().unwrap().invalidate(i);
+            for (node, state) in inserted {
+                self.leaf_data.on_added(&node, self.leaf_data.keep(state.borrow()));
+                self.journal.record(Change::Inserted(node.transfer_id));
+            }
+        }
+        self.channel = channel;
+        Ok(())
+    }
+
+    /// Insert leaves with the given transfer states, merging them into the
+    /// tree at once rather than shifting the leaves for each one.
+    ///
+    /// Either every state is inserted or none are. A batch with malformed
+    /// states or conflicting transfers fails with `Error::Batch`, which lists
+    /// each of them by position.
+    pub fn insert_many_hex<I>(&mut self, core_transfer_states: I) -> Result<(), Error>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        self.insert_nodes(
+            core_transfer_states
+                .into_iter()
+                .map(|state| format::hex_to_encoded(state.as_ref())),
+        )
+    }
+
+    /// Like `insert_many_hex`, with ABI encoded transfer states.
+    pub fn insert_many_bytes<I>(&mut self, core_transfer_states: I) -> Result<(), Error>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+    {
+        self.insert_nodes(
+            core_transfer_states
+                .into_iter()
+                .map(|state| format::slice_to_encoded(state.as_ref()).copied()),
+        )
+    }
+
+    fn upsert_node(&mut self, node: Node, state: &[u8; 384]) -> Option<Bytes32> {
+        match self
             .leaves
-            .binary_search_by_key(&&transfer_id, |n| &n.transfer_id)
+            .binary_search_by_key(&&node.transfer_id, |n| &n.transfer_id)
         {
-            self.leaves.remove(i);
+            Ok(i) => {
+                let previous = std::mem::replace(&mut self.leaves[i], node);
+                if previous.hash == node.hash {
+                    return Some(previous.hash);
+                }
+                self.cache.get_mut().unwrap().invalidate_path(i);
+                let previous_kept = self.leaf_data.on_removed(&previous);
+                self.leaf_data.on_added(&node, self.leaf_data.keep(state));
+                self.journal
+                    .record(Change::Replaced(previous, previous_kept));
+                Some(previous.hash)
+            }
+            Err(i) => {
+                self.leaves.insert(i, node);
+                self.cache.get_mut().unwrap().invalidate(i);
+                self.leaf_data.on_added(&node, self.leaf_data.keep(state));
+                self.journal.record(Change::Inserted(node.transfer_id));
+                None
+            }
+        }
+    }
+
+    /// Insert a leaf with the given transfer state, replacing the leaf of any
+    /// transfer with the same id. Returns the hash of the replaced leaf.
+    pub fn upsert_hex(&mut self, core_transfer_state: &str) -> Result<Option<Bytes32>, Error> {
+        let encoded = format::hex_to_encoded(core_transfer_state)?;
+        self.channel.check(&encoded)?;
+        Ok(self.upsert_node(format::encoded_to_node::<H>(&encoded), &encoded))
+    }
+
+    /// Like `upsert_hex`, with the decoded transfer state.
+    pub fn upsert_state(
+        &mut self,
+        core_transfer_state: &CoreTransferState,
+    ) -> Result<Option<Bytes32>, Error> {
+        let encoded = core_transfer_state.encode();
+        self.channel.check(&encoded)?;
+        Ok(self.upsert_node(format::encoded_to_node::<H>(&encoded), &encoded))
+    }
+
+    /// Remove the leaf corresponding to the transfer with a given id, returning
+    /// its hash or `None` if there was no such transfer.
+    pub fn delete_id(&mut self, transfer_id: Bytes32) -> Option<Bytes32> {
+        let i = self.index_of(transfer_id)?;
+        let node = self.leaves.remove(i);
+        self.cache.get_mut().unwrap().invalidate(i);
+        let kept = self.leaf_data.on_removed(&node);
+        self.journal.record(Change::Deleted(node, kept));
+        Some(node.hash)
+    }
+
+    /// Like `delete_id`, with the transfer id as a 0x-hex string.
+    pub fn delete_hex(&mut self, transfer_id: &str) -> Result<Option<Bytes32>, Error> {
+        let transfer_id = format::hex_to_bytes32(transfer_id)?;
+        Ok(self.delete_id(transfer_id))
+    }
+
+    /// Like `delete_id`, but fails with `Error::NotFound` if there was no such
+    /// transfer.
+    pub fn delete_id_strict(&mut self, transfer_id: Bytes32) -> Result<Bytes32, Error> {
+        self.delete_id(transfer_id)
+            .ok_or(Error::NotFound { transfer_id })
+    }
+
+    /// Remove the leaves of the transfers with the given ids in a single pass
+    /// over the tree, returning the transfer id and hash of each removed leaf
+    /// in order. Ids that are not in the tree are ignored.
+    pub fn delete_many<I: IntoIterator<Item = Bytes32>>(
+        &mut self,
+        transfer_ids: I,
+    ) -> Vec<(Bytes32, Bytes32)> {
+        let mut transfer_ids: Vec<_> = transfer_ids.into_iter().collect();
+        transfer_ids.sort_unstable();
+        let mut transfer_ids = transfer_ids.iter().peekable();
+
+        let mut index = 0;
+        let mut first_deleted = None;
+        let mut deleted = Vec::new();
+        let journal = &mut self.journal;
+        let leaf_data = &mut self.leaf_data;
+        self.leaves.retain(|leaf| {
+            while transfer_ids.next_if(|id| **id < leaf.transfer_id).is_some() {}
+            let delete = transfer_ids.peek() == Some(&&leaf.transfer_id);
+            if delete {
+                first_deleted.get_or_insert(index);
+                let kept = leaf_data.on_removed(leaf);
+                journal.record(Change::Deleted(*leaf, kept));
+                deleted.push((leaf.transfer_id, leaf.hash));
+            }
+            index += 1;
+            !delete
+        });
+        if let Some(i) = first_deleted {
+            self.cache.get_mut().unwrap().invalidate(i);
         }
+        deleted
     }
 
     /// It is intentional that this method is separate from insert/delete.
     /// One expected use-case is to insert, calculate a new hash, propose an
     /// update, fail, and finally need to roll back. To roll back the best thing to
-    /// do is just to delete without calculating the root.
+    /// do is just to delete without calculating the root, or to `rollback` to
+    /// a `checkpoint` taken before inserting.
+    ///
+    /// Internal nodes are kept between calls, and only those covering leaves
+    /// at or after the leftmost insert or delete since the last call are
+    /// recomputed. Inserting then deleting a leaf without asking for the root
+    /// in between costs no hashing at all.
     pub fn root(&self) -> Bytes32 {
-        if self.leaves.len() == 0 {
-            return Default::default();
-        }
+        self.cache.lock().unwrap().update::<H>(&self.leaves)
+    }
 
-        SCRATCH.with(|scratch| {
-            let mut scratch = scratch.borrow_mut();
+    /// Produces an inclusion proof for the transfer with the given id, or
+    /// `None` if the transfer is not in the tree.
+    ///
+    /// The siblings follow the same pairing as `root`, so a level where the
+    /// node is the odd one out and gets promoted contributes no sibling.
+    /// Siblings are read from the levels cached for `root`, so once those are
+    /// up to date a proof takes O(log n).
+    pub fn proof(&self, transfer_id: Bytes32) -> Option<Proof> {
+        let mut index = self.index_of(transfer_id)?;
+        let mut cache = self.cache.lock().unwrap();
+        cache.update::<H>(&self.leaves);
 
-            while scratch.len() < self.leaves.len() {
-                scratch.push(Default::default())
+        let mut siblings = Vec::new();
+        let mut directions = Vec::new();
+        let mut width = self.leaves.len();
+        let mut level = 0;
+        while width > 1 {
+            let sibling = index ^ 1;
+            if sibling < width {
+                siblings.push(cache.node(&self.leaves, level, sibling));
+                directions.push(sibling < index);
             }
+            index /= 2;
+            width = width.div_ceil(2);
+            level += 1;
+        }
 
-            let mut scratch = &mut scratch[..self.leaves.len()];
+        Some(Proof {
+            siblings,
+            directions,
+        })
+    }
+
+    /// Produces a single proof for several transfers in the layout expected by
+    /// OpenZeppelin's `MerkleProof.multiProofVerify`, so that siblings shared
+    /// between their paths are only included once.
+    ///
+    /// That verifier consumes nodes strictly first-in first-out, and every
+    /// leaf is taken before any hash it computes. A leaf promoted past a level
+    /// without a sibling pairs with a node that needs several of those hashes
+    /// when other transfers under that node are proven too, and the verifier
+    /// cannot wait for it. Such selections fail with
+    /// `Error::MultiproofOrder` and have to be split, for example proving the
+    /// promoted transfer on its own. Trees that do not sort pairs fail with
+    /// `Error::MultiproofNeedsSortedPairs`.
+    pub fn multiproof(&self, transfer_ids: &[Bytes32]) -> Result<MultiProof, Error> {
+        if !H::SORTED_PAIRS {
+            return Err(Error::MultiproofNeedsSortedPairs);
+        }
+        let mut indices = transfer_ids
+            .iter()
+            .map(|&transfer_id| {
+                self.index_of(transfer_id)
+                    .ok_or(Error::NotFound { transfer_id })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        indices.sort_unstable();
+        indices.dedup();
 
-            for i in 0..scratch.len() {
-                scratch[i] = self.leaves[i].hash;
+        let mut cache = self.cache.lock().unwrap();
+        let root = cache.update::<H>(&self.leaves);
+        let len = self.leaves.len();
+        let width = |level: usize| (len + (1 << level) - 1) >> level;
+        // Skips the levels where a node is promoted without a sibling, so that
+        // every node taken from the queue has a partner to be hashed with.
+        let lift = |mut level: usize, mut index: usize| {
+            while width(level) > 1 && index ^ 1 >= width(level) {
+                level += 1;
+                index /= 2;
             }
+            (level, index)
+        };
+        // Whether any of the leaves being proven sit under the node
+        let known = |level: usize, index: usize| {
+            let first = indices.partition_point(|&i| i < index << level);
+            first < indices.len() && indices[first] < (index + 1) << level
+        };
 
-            while scratch.len() > 1 {
-                let mut write = 0;
-                let mut read = 0;
-                while read + 1 < scratch.len() {
-                    let a = scratch[read];
-                    let b = scratch[read + 1];
-                    read += 2;
-                    scratch[write] = hash::combine(&a, &b);
-                    write += 1;
-                }
-                if read < scratch.len() {
-                    scratch[write] = scratch[read];
-                    write += 1;
-                }
+        let leaves: Vec<Bytes32> = indices.iter().map(|&i| self.leaves[i].hash).collect();
+        let mut queue: VecDeque<_> = indices
+            .iter()
+            .map(|&i| (lift(0, i), self.leaves[i].hash))
+            .collect();
+        let mut proof = Vec::new();
+        let mut proof_flags = Vec::new();
 
-                scratch = &mut scratch[0..write];
+        // Mirrors the verifier, which pairs the front of the queue either with
+        // the next node in the queue or with the next hash from the proof.
+        while let Some(((level, index), hash)) = queue.pop_front() {
+            if width(level) == 1 {
+                break;
             }
+            let sibling = index ^ 1;
+            let flag = known(level, sibling);
+            let sibling_hash = if flag {
+                match queue.pop_front() {
+                    Some((position, hash)) if position == (level, sibling) => hash,
+                    _ => {
+                        let first = indices.partition_point(|&i| i < index << level);
+                        return Err(Error::MultiproofOrder {
+                            transfer_id: self.leaves[indices[first]].transfer_id,
+                        });
+                    }
+                }
+            } else {
+                let hash = cache.node(&self.leaves, level, sibling);
+                proof.push(hash);
+                hash
+            };
+            proof_flags.push(flag);
+            queue.push_back((lift(level + 1, index / 2), H::combine(&hash, &sibling_hash)));
+        }
 
-            scratch[0]
+        // With nothing to prove the verifier takes the root from the proof.
+        if indices.is_empty() {
+            proof.push(root);
+        }
+
+        Ok(MultiProof {
+            leaves,
+            proof,
+            proof_flags,
         })
     }
+
+    /// Produces a proof that the transfer with the given id is not in the tree.
+    /// Trees that sort pairs cannot prove that their leaves are adjacent, and
+    /// fail with `Error::NonInclusionNeedsUnsortedPairs`.
+    ///
+    /// The proof holds the states of the neighbors of the id, so they must
+    /// have been inserted after `retain_states`, or this fails with
+    /// `Error::StateNotRetained`.
+    pub fn non_inclusion_proof(&self, transfer_id: Bytes32) -> Result<NonInclusionProof, Error> {
+        if H::SORTED_PAIRS {
+            return Err(Error::NonInclusionNeedsUnsortedPairs);
+        }
+        let i = match self
+            .leaves
+            .binary_search_by_key(&&transfer_id, |n| &n.transfer_id)
+        {
+            Ok(_) => return Err(Error::TransferIncluded { transfer_id }),
+            Err(i) => i,
+        };
+
+        let neighbor = |index: usize| {
+            let transfer_id = match self.leaves.get(index) {
+                Some(leaf) => leaf.transfer_id,
+                None => return Ok(None),
+            };
+            let state = self.leaf_data.kept[&transfer_id]
+                .state
+                .as_deref()
+                .ok_or(Error::StateNotRetained { transfer_id })?;
+            Ok(Some(Neighbor {
+                index,
+                core_transfer_state: *state,
+                proof: self.proof(transfer_id).expect("every leaf has a proof"),
+            }))
+        };
+
+        Ok(NonInclusionProof {
+            leaf_count: self.leaves.len(),
+            left: match i.checked_sub(1) {
+                Some(left) => neighbor(left)?,
+                None => None,
+            },
+            right: neighbor(i)?,
+        })
+    }
+
+    /// The number of transfers in the tree.
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    pub fn contains(&self, transfer_id: Bytes32) -> bool {
+        self.index_of(transfer_id).is_some()
+    }
+
+    /// The leaf hash of the transfer with the given id.
+    pub fn get(&self, transfer_id: Bytes32) -> Option<Bytes32> {
+        self.index_of(transfer_id).map(|i| self.leaves[i].hash)
+    }
+
+    /// The position of the transfer's leaf, counting from the lowest id.
+    pub fn index_of(&self, transfer_id: Bytes32) -> Option<usize> {
+        self.leaves
+            .binary_search_by_key(&&transfer_id, |n| &n.transfer_id)
+            .ok()
+    }
+
+    /// The transfer id and leaf hash of every leaf, in order of transfer id.
+    pub fn iter(
+        &self,
+    ) -> impl DoubleEndedIterator<Item = (Bytes32, Bytes32)> + ExactSizeIterator + '_ {
+        self.leaves.iter().map(|n| (n.transfer_id, n.hash))
+    }
+
+    /// The transfers with a timeout before the timestamp, as their transfer
+    /// id and timeout in order of timeout, soonest first.
+    pub fn expiring_before(
+        &self,
+        timestamp: U256,
+    ) -> impl Iterator<Item = (Bytes32, U256)> + '_ {
+        self.leaf_data
+            .timeouts
+            .range(..(timestamp, [0; 32]))
+            .map(|&(timeout, transfer_id)| (transfer_id, timeout))
+    }
+}
+
+/// Calculates the root of a run of leaves, pairing neighbors at each level and
+/// promoting the odd one out.
+///
+/// This works depth-first. Pairing level by level always gives the root a left
+/// child covering the largest power of two that is less than the number of
+/// leaves, so splitting there and recursing produces the same hashes while
+/// only holding one per level at any time.
+fn subtree_root<H: MerkleHasher>(leaves: &[Node]) -> Bytes32 {
+    match leaves.len() {
+        0 => Default::default(),
+        1 => leaves[0].hash,
+        len => {
+            let (left, right) = leaves.split_at(len.next_power_of_two() / 2);
+            H::combine(&subtree_root::<H>(left), &subtree_root::<H>(right))
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    use format::hex_to_node;
+    use format::{hex_encode, hex_to_node};
     use std::time::Instant;
     use test_utils::*;
 
@@ -191,6 +810,97 @@ mod tests {
         println!("{}", hex_encode(break_optimizer));
     }
 
+    /// Times `Tree::root`, both recomputing every level of the cache and after
+    /// a single insert, against pairing level by level through a scratch
+    /// buffer, which is how roots used to be calculated.
+    #[test]
+    #[ignore]
+    fn root_speed() {
+        fn breadth_first_root(leaves: &[Node], scratch: &mut Vec<Bytes32>) -> Bytes32 {
+            if leaves.is_empty() {
+                return Default::default();
+            }
+            scratch.clear();
+            scratch.extend(leaves.iter().map(|n| n.hash));
+            let mut len = scratch.len();
+            while len > 1 {
+                let mut write = 0;
+                let mut read = 0;
+                while read + 1 < len {
+                    scratch[write] = Keccak256::combine(&scratch[read], &scratch[read + 1]);
+                    read += 2;
+                    write += 1;
+                }
+                if read < len {
+                    scratch[write] = scratch[read];
+                    write += 1;
+                }
+                len = write;
+            }
+            scratch[0]
+        }
+
+        let mut tree = Tree::new();
+        for i in 0u32..5000 {
+            tree.insert_hex(&encoded_transfer(i)).unwrap();
+        }
+
+        let mut scratch = Vec::new();
+        let start = Instant::now();
+        let mut breadth_first = Bytes32::default();
+        for _ in 0..100 {
+            breadth_first = breadth_first_root(&tree.leaves, &mut scratch);
+        }
+        println!("breadth-first: {:?}", Instant::now() - start);
+
+        let start = Instant::now();
+        let mut root = Bytes32::default();
+        for _ in 0..100 {
+            tree.cache.get_mut().unwrap().invalidate(0);
+            root = tree.root();
+        }
+        println!("Tree::root, every level: {:?}", Instant::now() - start);
+        assert_eq!(breadth_first, root);
+
+        let start = Instant::now();
+        for i in 5000u32..5100 {
+            tree.insert_hex(&encoded_transfer(i)).unwrap();
+            root = tree.root();
+        }
+        println!("Tree::root, after an insert: {:?}", Instant::now() - start);
+        assert_eq!(breadth_first_root(&tree.leaves, &mut scratch), root);
+    }
+
+    #[test]
+    fn send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Tree>();
+    }
+
+    /// Transfers with known roots, inserted in this order.
+    const ENCODED_TRANSFERS: [&str; 20] = [
+        "0x000000000000000000000000ccc000000000000000000000000000000000000005549d00942c85d5004b75e5cd02acce4f330a7be6a8f6c5a1fabbf5b4cdd828000000000000000000000000def0000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001abcdef0000000000000000000000000000000000000000000000000000000000",
+        "0x000000000000000000000000ccc000000000000000000000000000000000000007ace82c0553bb5ca2aa65a36575e03c7f828862331d1c46dfb6670e4c4df42e000000000000000000000000def0000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001abcdef0000000000000000000000000000000000000000000000000000000000",
+        "0x000000000000000000000000ccc000000000000000000000000000000000000009f144821123db1bfc74c7ee6cbc8d165f5811c1e3e7ba7fe712c94fd8269c48000000000000000000000000def0000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001abcdef0000000000000000000000000000000000000000000000000000000000",
+        "0x000000000000000000000000ccc00000000000000000000000000000000000000dfe90e4da4d7751ed146dda5ddab63b79e9b289a1e5a8df3043c6f310d048e4000000000000000000000000def0000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001abcdef0000000000000000000000000000000000000000000000000000000000",
+        "0x000000000000000000000000ccc00000000000000000000000000000000000003299fc09866576090f3aac16947ac1d5609643de11bac44e1078f46c1d3e2d88000000000000000000000000def0000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001abcdef0000000000000000000000000000000000000000000000000000000000",
+        "0x000000000000000000000000ccc000000000000000000000000000000000000046e175ab10c6eda80a359597463ddab7964cde65aab637841ca4dae99cc70a03000000000000000000000000def0000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001abcdef0000000000000000000000000000000000000000000000000000000000",
+        "0x000000000000000000000000ccc00000000000000000000000000000000000004c58e396ee023abc1527ed314f5a8d5f400a3368db05fe94912052739bcc76c7000000000000000000000000def0000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001abcdef0000000000000000000000000000000000000000000000000000000000",
+        "0x000000000000000000000000ccc00000000000000000000000000000000000005dcb4070cac9caf98dc6d52e8c894d66ca56a604fddc5997e12b68ef3d157601000000000000000000000000def0000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001abcdef0000000000000000000000000000000000000000000000000000000000",
+        "0x000000000000000000000000ccc0000000000000000000000000000000000000601d0c7b09a5497afb3b40af416db9849b5aedccc28db446e5a4c820febe9f1e000000000000000000000000def0000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001abcdef0000000000000000000000000000000000000000000000000000000000",
+        "0x000000000000000000000000ccc0000000000000000000000000000000000000801577b9f75b982587cf3990dfc64f470737e8b541ea584d1a5f5256b4a93142000000000000000000000000def0000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001abcdef0000000000000000000000000000000000000000000000000000000000",
+        "0x000000000000000000000000ccc00000000000000000000000000000000000008aabe587d75461d49fa307817b2d8838a491f2c05651df8524ccd92bf452872d000000000000000000000000def0000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001abcdef0000000000000000000000000000000000000000000000000000000000",
+        "0x000000000000000000000000ccc00000000000000000000000000000000000009137e2f6cb80b96f774f8ed2373994541f66a8689f447087e69206427da05515000000000000000000000000def0000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001abcdef0000000000000000000000000000000000000000000000000000000000",
+        "0x000000000000000000000000ccc00000000000000000000000000000000000009552d4fead25e7e1e3b5ade019dcd6af4512f88872c6969b4d46022dbcc78284000000000000000000000000def0000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001abcdef0000000000000000000000000000000000000000000000000000000000",
+        "0x000000000000000000000000ccc00000000000000000000000000000000000009f85649dfee68d6fa25ec750fc8a0acb8249a35f2ec6fd9636d1d1ef03eaf2b3000000000000000000000000def0000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001abcdef0000000000000000000000000000000000000000000000000000000000",
+        "0x000000000000000000000000ccc0000000000000000000000000000000000000a3dcf8776fa5875dc396ab38acfe7281fa9ecf436f5b2726842c4b2d5fbcadc4000000000000000000000000def0000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001abcdef0000000000000000000000000000000000000000000000000000000000",
+        "0x000000000000000000000000ccc0000000000000000000000000000000000000a57a4368df0abc7d96913bc3f17979db404218797cc821ccaf290ee1fe585cbf000000000000000000000000def0000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001abcdef0000000000000000000000000000000000000000000000000000000000",
+        "0x000000000000000000000000ccc0000000000000000000000000000000000000aa29aab59197f98b58737c083159bf03c601671341d9022681ba17c4db88fb32000000000000000000000000def0000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001abcdef0000000000000000000000000000000000000000000000000000000000",
+        "0x000000000000000000000000ccc0000000000000000000000000000000000000ac5dc7ee2f3ee59658fdf9566663329ac102e66d19d629b5c3ba9af21aec9ffe000000000000000000000000def0000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001abcdef0000000000000000000000000000000000000000000000000000000000",
+        "0x000000000000000000000000ccc0000000000000000000000000000000000000dcd3135bc272cec12f4247fa064251e200c53c9ed6be8e644f8a1ba650c1a676000000000000000000000000def0000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001abcdef0000000000000000000000000000000000000000000000000000000000",
+        "0x000000000000000000000000ccc0000000000000000000000000000000000000e3efadc64aeb650945b72bac219c1633a632dc7d77925359b7e546cb88fa7f43000000000000000000000000def0000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001abcdef0000000000000000000000000000000000000000000000000000000000",
+    ];
+
     // Ensures that the result is the same as before.
     #[test]
     fn backward_compatible() {
@@ -249,9 +959,106 @@ mod tests {
         }
     }
 
+    /// Roots of the same transfers with leaves hashed twice, as OpenZeppelin's
+    /// StandardMerkleTree does.
+    #[test]
+    fn double_hashed_compatible() {
+        // Roots of OpenZeppelin's `StandardMerkleTree` for the first 1, 2, 4, 8
+        // and 16 transfers, from the leaf hashing and tree building of
+        // @openzeppelin/merkle-tree as `StandardMerkleTree.of(values,
+        // Array(12).fill("bytes32"), { sortLeaves: false })` does them, with the
+        // values being the words of each state in transfer id order. Its array
+        // layout only pairs leaves the way this tree does when their number is
+        // a power of two, so other sizes are not compared.
+        let results = [
+            "1422426802f0e58742e1ef9f2c2b5e97da377018626827328faab0e6c33d96ec",
+            "a025ab5c50305c3fe56d594e9b35f3b0e35d420c52c690c51e961b8a68a89211",
+            "f4f79b6b207376d998c23ee792a54528d709faeb5ec6fb93153903952e03c289",
+            "297162b26303ffd26519166892fe6b90e52e6bd22cbaa956c2e2e789dbf7a497",
+            "1e4d66b87d03ce2f3f98c94dca1c945014ddeb5e9acf717b48b8061d7444bb00",
+        ];
+
+        let mut tree = Tree::<DoubleHashed>::default();
+        let mut results = results.iter();
+        for (i, transfer) in ENCODED_TRANSFERS.iter().enumerate() {
+            tree.insert_hex(transfer)
+                .expect("Transfer id should be unique");
+            if (i + 1).is_power_of_two() {
+                assert_eq!(hex_encode(tree.root()), *results.next().unwrap());
+            }
+        }
+        assert_eq!(results.next(), None);
+
+        // Only the leaves differ, so proofs verify as usual.
+        let root = tree.root();
+        for leaf in tree.leaves.iter() {
+            let proof = tree.proof(leaf.transfer_id).unwrap();
+            assert!(verify(root, leaf.hash, &proof));
+        }
+    }
+
+    #[test]
+    fn positional_proofs() {
+        let mut tree = Tree::<Positional>::default();
+        for transfer in ENCODED_TRANSFERS.iter() {
+            tree.insert_hex(transfer).unwrap();
+        }
+        assert_eq!(
+            hex_encode(tree.root()),
+            "c5a2eb493cc21238e7f67ba894833b83cc4e06bfc1ef1b649dace952c427f74d"
+        );
+
+        for size in 1..=20 {
+            let mut tree = Tree::<Positional>::default();
+            for i in 0..size {
+                tree.insert_hex(&encoded_transfer((size, i))).unwrap();
+            }
+            let root = tree.root();
+            assert_eq!(root, subtree_root::<Positional>(&tree.leaves));
+
+            for (index, leaf) in tree.leaves.iter().enumerate() {
+                let proof = tree.proof(leaf.transfer_id).unwrap();
+                assert_eq!(proof.directions, proof::directions(index, size));
+                assert!(verify_with::<Positional>(root, leaf.hash, &proof));
+
+                // Claiming any other position fails
+                for i in 0..proof.directions.len() {
+                    let mut moved = proof.clone();
+                    moved.directions[i] = !moved.directions[i];
+                    assert!(!verify_with::<Positional>(root, leaf.hash, &moved));
+                }
+            }
+
+            let ids: Vec<_> = tree.leaves.iter().map(|n| n.transfer_id).collect();
+            assert_eq!(tree.multiproof(&ids), Err(Error::MultiproofNeedsSortedPairs));
+        }
+    }
+
+    #[test]
+    fn non_inclusion_positional() {
+        let tree = positional_tree(7);
+        let root = tree.root();
+        let ids: Vec<_> = tree.leaves.iter().map(|n| n.transfer_id).collect();
+
+        let absent = absent_after(ids[2]);
+        let proof = tree.non_inclusion_proof(absent).unwrap();
+        assert!(verify_non_inclusion_with::<Positional>(root, absent, &proof));
+
+        // Neighbors whose directions do not match their claimed indices
+        let mut shifted = proof;
+        if let Some(left) = shifted.left.as_mut() {
+            left.index = 4;
+        }
+        if let Some(right) = shifted.right.as_mut() {
+            right.index = 5;
+        }
+        assert!(!verify_non_inclusion_with::<Positional>(root, absent, &shifted));
+    }
+
     /// Verify that running deletes doesn't affect the result as compared
     /// to having created the tree from scratch
     #[test]
+    #[allow(clippy::len_zero)]
     fn set_unique() {
         let mut encoded_transfers = vec![
             "0x000000000000000000000000ccc000000000000000000000000000000000000005549d00942c85d5004b75e5cd02acce4f330a7be6a8f6c5a1fabbf5b4cdd828000000000000000000000000def0000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001abcdef0000000000000000000000000000000000000000000000000000000000",
@@ -298,6 +1105,760 @@ mod tests {
         }
     }
 
+    /// Every leaf's proof must verify against the root, for trees of every
+    /// shape of odd promotion.
+    #[test]
+    fn proofs_fold_to_root() {
+        for size in 1..=33 {
+            let mut tree = Tree::new();
+            let mut ids = Vec::new();
+            for i in 0..size {
+                let transfer = encoded_transfer((size, i));
+                ids.push(hex_to_node(&transfer).unwrap().transfer_id);
+                tree.insert_hex(&transfer).unwrap();
+            }
+            let root = tree.root();
+
+            for id in ids.iter() {
+                let proof = tree.proof(*id).unwrap();
+                let leaf = tree.leaves.iter().find(|n| &n.transfer_id == id).unwrap();
+                assert!(verify(root, leaf.hash, &proof));
+            }
+        }
+    }
+
+    #[test]
+    fn proof_missing() {
+        let mut tree = Tree::new();
+        assert_eq!(tree.proof(Default::default()), None);
+
+        let transfer = encoded_transfer(0);
+        tree.insert_hex(&transfer).unwrap();
+        assert_eq!(tree.proof(Default::default()), None);
+
+        // A lone leaf is the root, so nothing is needed to prove it.
+        let id = hex_to_node(&transfer).unwrap().transfer_id;
+        assert_eq!(
+            tree.proof(id),
+            Some(Proof {
+                siblings: vec![],
+                directions: vec![]
+            })
+        );
+    }
+
+    #[test]
+    fn multiproofs_verify() {
+        for size in 1..=20 {
+            let mut tree = Tree::new();
+            for i in 0..size {
+                tree.insert_hex(&encoded_transfer((size, i))).unwrap();
+            }
+            let root = tree.root();
+            let ids: Vec<_> = tree.leaves.iter().map(|n| n.transfer_id).collect();
+
+            for subset in 0u32..(1 << size.min(8)) {
+                let selected: Vec<_> = (0..size)
+                    .filter(|i| subset & (1 << (i % 8)) != 0)
+                    .map(|i| ids[i])
+                    .collect();
+                // Not every selection can be expressed, but sizes that are a
+                // power of two never promote a node and always can.
+                match tree.multiproof(&selected) {
+                    Ok(multiproof) => assert!(verify_multiproof(root, &multiproof)),
+                    Err(Error::MultiproofOrder { transfer_id }) => {
+                        assert!(!size.is_power_of_two());
+                        assert!(selected.contains(&transfer_id));
+                    }
+                    Err(error) => panic!("{}", error),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn multiproof_promoted_leaf() {
+        let mut tree = Tree::new();
+        for i in 0..5 {
+            tree.insert_hex(&encoded_transfer(i)).unwrap();
+        }
+        let ids: Vec<_> = tree.leaves.iter().map(|n| n.transfer_id).collect();
+
+        // The last leaf is promoted twice, so it pairs with a node that needs
+        // two hashes from the first leaf. The verifier's queue cannot wait.
+        let promoted = Err(Error::MultiproofOrder {
+            transfer_id: ids[4],
+        });
+        assert_eq!(tree.multiproof(&[ids[0], ids[4]]), promoted);
+        assert_eq!(tree.multiproof(&ids), promoted);
+
+        // Leaving it out, or proving it on its own, works.
+        let multiproof = tree.multiproof(&ids[..4]).unwrap();
+        assert_eq!(multiproof.proof, vec![tree.leaves[4].hash]);
+        assert!(verify_multiproof(tree.root(), &multiproof));
+
+        // Alone it is fine, and matches the single proof.
+        let multiproof = tree.multiproof(&[ids[4]]).unwrap();
+        assert_eq!(multiproof.proof, tree.proof(ids[4]).unwrap().siblings);
+        assert!(verify_multiproof(tree.root(), &multiproof));
+
+        assert_eq!(
+            tree.multiproof(&[ids[0], Default::default()]),
+            Err(Error::NotFound {
+                transfer_id: Default::default()
+            })
+        );
+    }
+
+    fn positional_tree(size: usize) -> Tree<Positional> {
+        let mut tree = Tree::default();
+        tree.retain_states();
+        for i in 0..size {
+            tree.insert_hex(&encoded_transfer((size, i))).unwrap();
+        }
+        tree
+    }
+
+    /// The state kept for a transfer of a tree that retains states.
+    fn kept_state<H>(tree: &Tree<H>, transfer_id: Bytes32) -> CoreTransferState {
+        let state = tree.leaf_data.kept[&transfer_id].state.as_ref().unwrap();
+        CoreTransferState::decode(state).unwrap()
+    }
+
+    #[test]
+    fn non_inclusion_needs_retained_states() {
+        let mut tree = Tree::<Positional>::default();
+        for i in 0..3 {
+            tree.insert_hex(&encoded_transfer(i)).unwrap();
+        }
+        tree.retain_states();
+        for i in 3..6 {
+            tree.insert_hex(&encoded_transfer(i)).unwrap();
+        }
+        let ids: Vec<_> = tree.leaves.iter().map(|n| n.transfer_id).collect();
+        let states: Vec<_> = ids
+            .iter()
+            .map(|id| tree.leaf_data.kept[id].state.is_some())
+            .collect();
+        assert_eq!(states.iter().filter(|&&kept| kept).count(), 3);
+
+        // Each absent id between two leaves needs both of their states
+        for (pair, kept) in ids.windows(2).zip(states.windows(2)) {
+            let proof = tree.non_inclusion_proof(absent_after(pair[0]));
+            match kept.iter().position(|kept| !kept) {
+                Some(side) => assert_eq!(
+                    proof,
+                    Err(Error::StateNotRetained {
+                        transfer_id: pair[side]
+                    })
+                ),
+                None => assert!(proof.is_ok()),
+            }
+        }
+
+        // A rollback does not bring back states once they are discarded
+        let checkpoint = tree.checkpoint();
+        tree.delete_many(ids.iter().copied());
+        tree.discard_states();
+        tree.rollback(checkpoint).unwrap();
+        assert_eq!(tree.len(), 6);
+        assert!(tree.leaf_data.kept.values().all(|kept| kept.state.is_none()));
+    }
+
+    #[test]
+    fn non_inclusion_proofs_verify() {
+        for size in 0..=12 {
+            let tree = positional_tree(size);
+            let root = tree.root();
+
+            // Ids just past each leaf are absent, as is the zero id.
+            let mut absent = vec![Bytes32::default()];
+            for leaf in tree.leaves.iter() {
+                absent.push(absent_after(leaf.transfer_id));
+                assert_eq!(
+                    tree.non_inclusion_proof(leaf.transfer_id),
+                    Err(Error::TransferIncluded {
+                        transfer_id: leaf.transfer_id
+                    })
+                );
+            }
+
+            for id in absent {
+                let proof = tree.non_inclusion_proof(id).unwrap();
+                assert!(verify_non_inclusion_with::<Positional>(root, id, &proof));
+            }
+        }
+
+        // Sorted pairs do not bind leaves to indices
+        let mut tree = Tree::new();
+        tree.insert_hex(&encoded_transfer(0)).unwrap();
+        assert_eq!(
+            tree.non_inclusion_proof(Bytes32::default()),
+            Err(Error::NonInclusionNeedsUnsortedPairs)
+        );
+    }
+
+    #[test]
+    fn non_inclusion_rejects_gaps() {
+        let tree = positional_tree(7);
+        let root = tree.root();
+        let ids: Vec<_> = tree.leaves.iter().map(|n| n.transfer_id).collect();
+
+        let absent = absent_after(ids[2]);
+        let proof = tree.non_inclusion_proof(absent).unwrap();
+        assert!(verify_non_inclusion_with::<Positional>(root, absent, &proof));
+
+        // An id that is present, sitting between the neighbors
+        assert!(!verify_non_inclusion_with::<Positional>(root, ids[3], &proof));
+
+        // Relabelling a neighbor so that a present id seems to fall in the gap
+        let mut relabelled = proof.clone();
+        if let Some(right) = relabelled.right.as_mut() {
+            right.core_transfer_state[32..64].copy_from_slice(&absent_after(ids[3]));
+        }
+        assert!(!verify_non_inclusion_with::<Positional>(root, ids[3], &relabelled));
+
+        // Skipping over a leaf by claiming a neighbor further along
+        let mut skipping = proof.clone();
+        skipping.right = tree
+            .non_inclusion_proof(absent_after(ids[3]))
+            .unwrap()
+            .right;
+        assert!(!verify_non_inclusion_with::<Positional>(root, absent, &skipping));
+
+        // Lying about the index to make the neighbors look adjacent
+        if let Some(right) = skipping.right.as_mut() {
+            right.index = 3;
+        }
+        assert!(!verify_non_inclusion_with::<Positional>(root, absent, &skipping));
+
+        // Dropping a neighbor
+        let mut dropped = proof;
+        dropped.right = None;
+        assert!(!verify_non_inclusion_with::<Positional>(root, absent, &dropped));
+
+        // Leaves 0 and 2 of four claimed at indices 1 and 2. Their sorted pair
+        // proofs both hold, so hashers that sort pairs are refused outright.
+        let mut sorted = Tree::new();
+        sorted.retain_states();
+        for i in 0..4 {
+            sorted.insert_hex(&encoded_transfer(i)).unwrap();
+        }
+        let neighbor = |index: usize, leaf: usize| {
+            let transfer_id = sorted.leaves[leaf].transfer_id;
+            let mut proof = sorted.proof(transfer_id).unwrap();
+            // Sorted pairs ignore the directions, so they can be made to match
+            proof.directions = proof::directions(index, 4);
+            assert!(verify(sorted.root(), sorted.leaves[leaf].hash, &proof));
+            Neighbor {
+                index,
+                core_transfer_state: kept_state(&sorted, transfer_id).encode(),
+                proof,
+            }
+        };
+        let forged = NonInclusionProof {
+            leaf_count: 4,
+            left: Some(neighbor(1, 0)),
+            right: Some(neighbor(2, 2)),
+        };
+        let between = absent_after(sorted.leaves[0].transfer_id);
+        assert!(!verify_non_inclusion_with::<Keccak256>(
+            sorted.root(),
+            between,
+            &forged
+        ));
+        assert!(!verify_non_inclusion_with::<Positional>(sorted.root(), between, &forged));
+    }
+
+    fn absent_after(mut id: Bytes32) -> Bytes32 {
+        id[31] = id[31].wrapping_add(1);
+        id
+    }
+
+    /// The cached root must match hashing the leaves from scratch through any
+    /// sequence of inserts, replacements and deletes, including ones skipped
+    /// between roots.
+    #[test]
+    fn cached_root_matches() {
+        let mut tree = Tree::new();
+        tree.retain_states();
+        let mut ids = Vec::new();
+        for step in 0u64..400 {
+            let r = rand(step);
+            if !ids.is_empty() && r % 7 == 0 {
+                let id = ids[(r as usize / 7) % ids.len()];
+                let mut state = kept_state(&tree, id);
+                state.transfer_timeout += 1;
+                tree.upsert_state(&state).unwrap();
+            } else if ids.is_empty() || r % 3 != 0 {
+                let transfer = encoded_transfer(step);
+                ids.push(hex_to_node(&transfer).unwrap().transfer_id);
+                tree.insert_hex(&transfer).unwrap();
+            } else {
+                let id = ids.swap_remove((r as usize / 3) % ids.len());
+                tree.delete_id(id);
+            }
+            if r % 5 != 0 {
+                assert_eq!(tree.root(), subtree_root::<Keccak256>(&tree.leaves));
+            }
+        }
+
+        while let Some(id) = ids.pop() {
+            tree.delete_id(id);
+            assert_eq!(tree.root(), subtree_root::<Keccak256>(&tree.leaves));
+        }
+        assert_eq!(tree.root(), Bytes32::default());
+
+        // Clones carry the cache along but update independently
+        tree.insert_hex(&encoded_transfer(0)).unwrap();
+        tree.root();
+        let mut copy = tree.clone();
+        copy.insert_hex(&encoded_transfer(1)).unwrap();
+        assert_eq!(copy.root(), subtree_root::<Keccak256>(&copy.leaves));
+        assert_eq!(tree.root(), subtree_root::<Keccak256>(&tree.leaves));
+    }
+
+    thread_local! {
+        static PAIRS_HASHED: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    /// Keccak-256, counting the pairs it hashes.
+    struct Counting;
+
+    impl MerkleHasher for Counting {
+        fn hash_leaf(data: &[u8]) -> Bytes32 {
+            Keccak256::hash_leaf(data)
+        }
+
+        fn hash_pair(left: &Bytes32, right: &Bytes32) -> Bytes32 {
+            PAIRS_HASHED.with(|count| count.set(count.get() + 1));
+            Keccak256::hash_pair(left, right)
+        }
+    }
+
+    /// Replacing a leaf in place only rehashes the nodes above it, while
+    /// inserting one rehashes everything to its right.
+    #[test]
+    fn replacing_rehashes_one_path() {
+        let mut tree = Tree::<Counting>::default();
+        tree.retain_states();
+        for i in 0..16 {
+            tree.insert_hex(&encoded_transfer(i)).unwrap();
+        }
+        tree.root();
+        let pairs_hashed = |tree: &Tree<Counting>| {
+            PAIRS_HASHED.with(|count| count.set(0));
+            let root = tree.root();
+            assert_eq!(root, subtree_root::<Counting>(&tree.leaves));
+            PAIRS_HASHED.with(|count| count.get()) - 15
+        };
+        pairs_hashed(&tree);
+
+        let first = tree.leaves[0].transfer_id;
+        let mut state = kept_state(&tree, first);
+        state.transfer_timeout += 1;
+        tree.upsert_state(&state).unwrap();
+        assert_eq!(pairs_hashed(&tree), 4);
+
+        // Two replacements that share the upper part of their paths
+        let ids: Vec<_> = tree.leaves.iter().map(|n| n.transfer_id).collect();
+        for &id in &ids[4..6] {
+            let mut state = kept_state(&tree, id);
+            state.transfer_timeout += 1;
+            tree.upsert_state(&state).unwrap();
+        }
+        assert_eq!(pairs_hashed(&tree), 4);
+
+        tree.delete_id(ids[0]);
+        tree.insert_hex(&encoded_transfer(16)).unwrap();
+        assert!(pairs_hashed(&tree) > 4);
+    }
+
+    #[test]
+    fn insert_bytes_same_as_hex() {
+        let mut from_hex = Tree::new();
+        let mut from_bytes = Tree::new();
+        for i in 0..10 {
+            let transfer = encoded_transfer(i);
+            let mut bytes = [0u8; 384];
+            faster_hex::hex_decode(&transfer.as_bytes()[2..], &mut bytes).unwrap();
+
+            from_hex.insert_hex(&transfer).unwrap();
+            from_bytes.insert_bytes(&bytes).unwrap();
+            assert_eq!(from_hex.root(), from_bytes.root());
+        }
+
+        assert_eq!(
+            from_bytes.insert_bytes(&[0u8; 383]),
+            Err(Error::WrongLength {
+                expected: 384,
+                got: 383
+            })
+        );
+    }
+
+    #[test]
+    fn insert_state_same_as_hex() {
+        let transfer = encoded_transfer(0);
+        let state = CoreTransferState::from_hex(&transfer).unwrap();
+
+        let mut from_hex = Tree::new();
+        from_hex.insert_hex(&transfer).unwrap();
+        let mut from_state = Tree::new();
+        from_state.insert_state(&state).unwrap();
+        assert_eq!(from_hex.root(), from_state.root());
+    }
+
+    #[test]
+    fn duplicate_transfer_id() {
+        let transfer = encoded_transfer(0);
+        let mut tree = Tree::new();
+        tree.insert_hex(&transfer).unwrap();
+        // Inserting the same state again is fine
+        tree.insert_hex(&transfer).unwrap();
+
+        let mut state = CoreTransferState::from_hex(&transfer).unwrap();
+        state.transfer_timeout += 1;
+        let existing = hex_to_node(&transfer).unwrap();
+        assert_eq!(
+            tree.insert_state(&state),
+            Err(Error::DuplicateTransferID {
+                transfer_id: existing.transfer_id,
+                existing: existing.hash,
+                inserted: hash::keccak(&state.encode()),
+            })
+        );
+    }
+
+    #[test]
+    fn upsert_replaces_leaf() {
+        let transfer = encoded_transfer(0);
+        let existing = hex_to_node(&transfer).unwrap();
+        let mut tree = Tree::new();
+        assert_eq!(tree.upsert_hex(&transfer), Ok(None));
+        assert_eq!(tree.upsert_hex(&transfer), Ok(Some(existing.hash)));
+        tree.insert_hex(&encoded_transfer(1)).unwrap();
+        tree.root();
+
+        let mut state = CoreTransferState::from_hex(&transfer).unwrap();
+        state.transfer_timeout += 1;
+        assert_eq!(tree.upsert_state(&state), Ok(Some(existing.hash)));
+
+        // Same as if the new state had been inserted in the first place
+        let mut expected = Tree::new();
+        expected.insert_state(&state).unwrap();
+        expected.insert_hex(&encoded_transfer(1)).unwrap();
+        assert_eq!(tree.root(), expected.root());
+        assert_eq!(tree.leaves, expected.leaves);
+
+        assert!(tree.upsert_hex("0x").is_err());
+    }
+
+    #[test]
+    fn insert_many_same_as_one_by_one() {
+        let mut one_by_one = Tree::new();
+        for transfer in ENCODED_TRANSFERS.iter() {
+            one_by_one.insert_hex(transfer).unwrap();
+        }
+
+        // In two batches that overlap, on top of the cached root of the first
+        let mut batched = Tree::new();
+        batched.insert_many_hex(&ENCODED_TRANSFERS[..12]).unwrap();
+        batched.root();
+        batched.insert_many_hex(&ENCODED_TRANSFERS[8..]).unwrap();
+        assert_eq!(batched.leaves, one_by_one.leaves);
+        assert_eq!(batched.root(), one_by_one.root());
+
+        let mut from_bytes = Tree::new();
+        from_bytes
+            .insert_many_bytes(ENCODED_TRANSFERS.iter().map(|transfer| {
+                let mut bytes = vec![0u8; 384];
+                faster_hex::hex_decode(&transfer.as_bytes()[2..], &mut bytes).unwrap();
+                bytes
+            }))
+            .unwrap();
+        assert_eq!(from_bytes.root(), one_by_one.root());
+
+        // Removing every other transfer at once
+        let deleted: Vec<_> = one_by_one
+            .leaves
+            .iter()
+            .step_by(2)
+            .map(|n| n.transfer_id)
+            .collect();
+        for id in deleted.iter() {
+            one_by_one.delete_id(*id);
+        }
+        batched.delete_many(
+            deleted
+                .iter()
+                .rev()
+                .copied()
+                .chain(Some(Bytes32::default())),
+        );
+        assert_eq!(batched.leaves, one_by_one.leaves);
+        assert_eq!(batched.root(), one_by_one.root());
+    }
+
+    #[test]
+    fn insert_many_reports_every_conflict() {
+        let mut tree = Tree::new();
+        tree.insert_hex(&encoded_transfer(0)).unwrap();
+        let root = tree.root();
+
+        let mut changed = transfer(0);
+        changed.transfer_timeout += 1;
+        let changed = changed.encode();
+        let mut changed_again = transfer(1);
+        changed_again.transfer_timeout += 1;
+        let changed_again = changed_again.encode();
+
+        let node = |transfer: &str| hex_to_node(transfer).unwrap();
+        let batch = vec![
+            encoded_transfer(1),
+            // Conflicts with the tree
+            format!("0x{}", faster_hex::hex_string(&changed).unwrap()),
+            "0x".to_owned(),
+            // Conflicts with the first item
+            format!("0x{}", faster_hex::hex_string(&changed_again).unwrap()),
+            // Repeating an item is fine
+            encoded_transfer(1),
+            encoded_transfer(2),
+        ];
+        assert_eq!(
+            tree.insert_many_hex(&batch),
+            Err(Error::Batch {
+                errors: vec![
+                    (
+                        1,
+                        Error::DuplicateTransferID {
+                            transfer_id: node(&encoded_transfer(0)).transfer_id,
+                            existing: node(&encoded_transfer(0)).hash,
+                            inserted: hash::keccak(&changed),
+                        }
+                    ),
+                    (
+                        2,
+                        Error::WrongLength {
+                            expected: 770,
+                            got: 2
+                        }
+                    ),
+                    (
+                        3,
+                        Error::DuplicateTransferID {
+                            transfer_id: node(&encoded_transfer(1)).transfer_id,
+                            existing: node(&encoded_transfer(1)).hash,
+                            inserted: hash::keccak(&changed_again),
+                        }
+                    ),
+                ]
+            })
+        );
+
+        // Nothing was inserted
+        assert_eq!(tree.leaves.len(), 1);
+        assert_eq!(tree.root(), root);
+    }
+
+    #[test]
+    fn queries() {
+        let mut tree = Tree::new();
+        assert!(tree.is_empty());
+        assert_eq!(tree.iter().next(), None);
+
+        let mut expected = Vec::new();
+        for i in 0..5 {
+            let transfer = encoded_transfer(i);
+            let node = hex_to_node(&transfer).unwrap();
+            expected.push((node.transfer_id, node.hash));
+            tree.insert_hex(&transfer).unwrap();
+        }
+        expected.sort();
+
+        assert_eq!(tree.len(), 5);
+        assert!(!tree.is_empty());
+        assert_eq!(tree.iter().collect::<Vec<_>>(), expected);
+        for (i, (transfer_id, hash)) in expected.iter().enumerate() {
+            assert!(tree.contains(*transfer_id));
+            assert_eq!(tree.get(*transfer_id), Some(*hash));
+            assert_eq!(tree.index_of(*transfer_id), Some(i));
+        }
+
+        let absent = absent_after(expected[0].0);
+        assert!(!tree.contains(absent));
+        assert_eq!(tree.get(absent), None);
+        assert_eq!(tree.index_of(absent), None);
+    }
+
+    #[test]
+    fn expiring_before() {
+        let mut tree = Tree::new();
+        let mut states = Vec::new();
+        for i in 0..20u64 {
+            let mut state = transfer(i);
+            state.transfer_timeout = U256::from(1000 + rand(i) % 100);
+            tree.insert_state(&state).unwrap();
+            states.push(state);
+        }
+
+        let check = |tree: &Tree, states: &[CoreTransferState]| {
+            let mut expected: Vec<_> = states
+                .iter()
+                .map(|s| (s.transfer_timeout, s.transfer_id))
+                .collect();
+            expected.sort();
+            for &timestamp in &[0u64, 1000, 1050, 1100, u64::MAX] {
+                let timestamp = U256::from(timestamp);
+                let expiring = expected
+                    .iter()
+                    .filter(|(timeout, _)| *timeout < timestamp)
+                    .map(|&(timeout, transfer_id)| (transfer_id, timeout));
+                assert!(tree.expiring_before(timestamp).eq(expiring));
+            }
+        };
+        check(&tree, &states);
+
+        // Replacing a state updates its timeout
+        states[3].transfer_timeout = U256::from(1020u32);
+        tree.upsert_state(&states[3]).unwrap();
+        check(&tree, &states);
+
+        tree.delete_id(states[5].transfer_id);
+        states.remove(5);
+        check(&tree, &states);
+    }
+
+    #[test]
+    fn deletes_report_removed_leaf() {
+        let mut tree = Tree::new();
+        let mut nodes = Vec::new();
+        for i in 0..4 {
+            let transfer = encoded_transfer(i);
+            nodes.push(hex_to_node(&transfer).unwrap());
+            tree.insert_hex(&transfer).unwrap();
+        }
+        nodes.sort_by_key(|n| n.transfer_id);
+
+        let id = nodes[0].transfer_id;
+        assert_eq!(tree.delete_id(id), Some(nodes[0].hash));
+        assert_eq!(tree.delete_id(id), None);
+        assert_eq!(
+            tree.delete_id_strict(id),
+            Err(Error::NotFound { transfer_id: id })
+        );
+
+        let hex = format!("0x{}", hex_encode(nodes[1].transfer_id));
+        assert_eq!(tree.delete_hex(&hex), Ok(Some(nodes[1].hash)));
+        assert_eq!(tree.delete_hex(&hex), Ok(None));
+        assert_eq!(
+            tree.delete_hex("0x00"),
+            Err(Error::WrongLength {
+                expected: 66,
+                got: 4
+            })
+        );
+
+        assert_eq!(
+            tree.delete_id_strict(nodes[2].transfer_id),
+            Ok(nodes[2].hash)
+        );
+        assert_eq!(
+            tree.delete_many(vec![nodes[3].transfer_id, id]),
+            vec![(nodes[3].transfer_id, nodes[3].hash)]
+        );
+        assert!(tree.is_empty());
+    }
+
+    #[test]
+    fn channel_binding() {
+        let state = transfer(0);
+        let channel = state.channel_address;
+        let mut other = transfer(1);
+        other.channel_address[19] ^= 1;
+        let other_hex = format!("0x{}", faster_hex::hex_string(&other.encode()).unwrap());
+        let mismatch = Error::ChannelMismatch {
+            expected: channel,
+            got: other.channel_address,
+        };
+
+        // Unbound trees take transfers from any channel
+        let mut tree = Tree::new();
+        assert_eq!(tree.channel(), None);
+        tree.insert_state(&state).unwrap();
+        tree.insert_hex(&other_hex).unwrap();
+
+        let mut tree: Tree = Tree::with_channel(channel);
+        assert_eq!(tree.insert_hex(&other_hex), Err(mismatch.clone()));
+        assert_eq!(tree.upsert_state(&other), Err(mismatch.clone()));
+        assert!(tree.is_empty());
+        tree.insert_state(&state).unwrap();
+        assert_eq!(
+            tree.insert_many_hex(&[encoded_transfer(2), other_hex.clone()]),
+            Err(Error::Batch {
+                errors: vec![(1, mismatch.clone())]
+            })
+        );
+        assert_eq!(tree.len(), 1);
+
+        // A failed insert does not bind the tree
+        let mut tree: Tree = Tree::bound_on_first_insert();
+        assert!(tree.insert_hex("0x").is_err());
+        tree.insert_state(&state).unwrap();
+        tree.insert_state(&state).unwrap();
+        assert_eq!(tree.channel(), Some(channel));
+        assert_eq!(tree.insert_hex(&other_hex), Err(mismatch));
+
+        // Nor does a failed batch, but one that succeeds binds to its first
+        let mut tree: Tree = Tree::bound_on_first_insert();
+        let batch = [encoded_transfer(0), other_hex];
+        assert!(tree.insert_many_hex(&batch).is_err());
+        assert_eq!(tree.channel(), None);
+        tree.insert_many_hex(&batch[1..]).unwrap();
+        assert_eq!(tree.channel(), Some(other.channel_address));
+    }
+
+    /// A stand-in for another hash function, to check that nothing is
+    /// hard-wired to Keccak-256.
+    struct Prefixed;
+
+    impl MerkleHasher for Prefixed {
+        fn hash_leaf(data: &[u8]) -> Bytes32 {
+            hash::keccak(&[b"leaf", data].concat())
+        }
+
+        fn hash_pair(left: &Bytes32, right: &Bytes32) -> Bytes32 {
+            hash::keccak(&[&b"pair"[..], left, right].concat())
+        }
+    }
+
+    #[test]
+    fn custom_hasher() {
+        let mut keccak = Tree::new();
+        let mut prefixed = Tree::<Prefixed>::default();
+        for i in 0..7 {
+            keccak.insert_hex(&encoded_transfer(i)).unwrap();
+            prefixed.insert_hex(&encoded_transfer(i)).unwrap();
+        }
+        let root = prefixed.root();
+        assert_ne!(root, keccak.root());
+        assert_eq!(root, subtree_root::<Prefixed>(&prefixed.leaves));
+
+        for leaf in prefixed.leaves.iter() {
+            let proof = prefixed.proof(leaf.transfer_id).unwrap();
+            assert!(verify_with::<Prefixed>(root, leaf.hash, &proof));
+            assert!(!verify(root, leaf.hash, &proof));
+        }
+
+        // A snapshot only restores with the hasher that produced it
+        let bytes = prefixed.to_bytes();
+        assert!(Tree::<Prefixed>::from_bytes(&bytes).is_ok());
+        assert!(matches!(
+            Tree::<Keccak256>::from_bytes(&bytes),
+            Err(Error::RootMismatch { .. })
+        ));
+    }
+
     #[test]
     fn empty_set() {
         let root = Tree::new().root();
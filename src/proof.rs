@@ -0,0 +1,318 @@
+use super::{Bytes32, Keccak256, MerkleHasher};
+use std::convert::TryInto;
+
+/// An inclusion proof for a single leaf of a `Tree`.
+///
+/// Sibling hashes are ordered from the leaf up towards the root. With the
+/// default sorted pairs the directions are not needed, and the siblings can be
+/// passed directly to OpenZeppelin's `MerkleProof.verify`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Proof {
+    pub siblings: Vec<Bytes32>,
+    /// Whether each sibling is the left one of its pair.
+    pub directions: Vec<bool>,
+}
+
+/// Checks that the leaf hash is included under the root, without needing
+/// the tree that produced the proof.
+pub fn verify(root: Bytes32, leaf_hash: Bytes32, proof: &Proof) -> bool {
+    verify_with::<Keccak256>(root, leaf_hash, proof)
+}
+
+/// Like `verify`, for a tree built with a different hasher.
+///
+/// Directions are only read when `H` does not sort pairs, in which case there
+/// must be one for every sibling.
+pub fn verify_with<H: MerkleHasher>(root: Bytes32, leaf_hash: Bytes32, proof: &Proof) -> bool {
+    if !H::SORTED_PAIRS && proof.directions.len() != proof.siblings.len() {
+        return false;
+    }
+    let computed = proof
+        .siblings
+        .iter()
+        .enumerate()
+        .fold(leaf_hash, |acc, (i, sibling)| {
+            let left = proof.directions.get(i) == Some(&true);
+            combine_with::<H>(&acc, sibling, left)
+        });
+    computed == root
+}
+
+/// Hashes a node with its sibling, which is on the left if `left` is set.
+fn combine_with<H: MerkleHasher>(node: &Bytes32, sibling: &Bytes32, left: bool) -> Bytes32 {
+    if left {
+        H::combine(sibling, node)
+    } else {
+        H::combine(node, sibling)
+    }
+}
+
+/// A proof for several leaves at once, laid out for OpenZeppelin's
+/// `MerkleProof.multiProofVerify`.
+///
+/// The verifier must be given the leaf hashes in the order of `leaves`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultiProof {
+    pub leaves: Vec<Bytes32>,
+    pub proof: Vec<Bytes32>,
+    pub proof_flags: Vec<bool>,
+}
+
+/// Checks that every leaf of the multiproof is included under the root.
+///
+/// Follows `multiProofVerify`: each step hashes the next leaf or computed node
+/// with either the one after it (when the flag is set) or the next proof hash.
+pub fn verify_multiproof(root: Bytes32, multiproof: &MultiProof) -> bool {
+    verify_multiproof_with::<Keccak256>(root, multiproof)
+}
+
+/// Like `verify_multiproof`, for a tree built with a different hasher.
+pub fn verify_multiproof_with<H: MerkleHasher>(root: Bytes32, multiproof: &MultiProof) -> bool {
+    let MultiProof {
+        leaves,
+        proof,
+        proof_flags,
+    } = multiproof;
+
+    if leaves.len() + proof.len() != proof_flags.len() + 1 {
+        return false;
+    }
+
+    let mut hashes = Vec::with_capacity(proof_flags.len());
+    let mut leaf_pos = 0;
+    let mut hash_pos = 0;
+    let mut proof_pos = 0;
+    let mut next = |hashes: &Vec<Bytes32>| {
+        if leaf_pos < leaves.len() {
+            leaf_pos += 1;
+            Some(leaves[leaf_pos - 1])
+        } else {
+            hash_pos += 1;
+            hashes.get(hash_pos - 1).copied()
+        }
+    };
+
+    for &flag in proof_flags {
+        let a = next(&hashes);
+        let b = if flag {
+            next(&hashes)
+        } else {
+            proof_pos += 1;
+            proof.get(proof_pos - 1).copied()
+        };
+        match (a, b) {
+            (Some(a), Some(b)) => hashes.push(H::combine(&a, &b)),
+            _ => return false,
+        }
+    }
+
+    let computed = if let Some(last) = hashes.last() {
+        if proof_pos != proof.len() {
+            return false;
+        }
+        *last
+    } else if let Some(leaf) = leaves.first() {
+        *leaf
+    } else {
+        proof[0]
+    };
+    computed == root
+}
+
+/// Shows that a transfer id is absent from a tree by proving the inclusion of
+/// the leaves immediately before and after where it would be sorted.
+///
+/// A missing neighbor means the id would sort first or last. Both are missing
+/// only for the empty tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NonInclusionProof {
+    /// The number of leaves in the tree, as claimed by the prover. The root
+    /// does not commit to it, so the verifier only learns that the neighbors
+    /// are adjacent in a tree of this size whose root matches.
+    pub leaf_count: usize,
+    pub left: Option<Neighbor>,
+    pub right: Option<Neighbor>,
+}
+
+/// A leaf next to an absent transfer id, with its position and inclusion proof.
+///
+/// The leaf is given by its ABI encoded transfer state rather than its hash, so
+/// that the verifier can check which transfer id the leaf belongs to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Neighbor {
+    pub index: usize,
+    pub core_transfer_state: [u8; 384],
+    pub proof: Proof,
+}
+
+impl Neighbor {
+    pub fn transfer_id(&self) -> Bytes32 {
+        self.core_transfer_state[32..64].try_into().unwrap()
+    }
+
+    fn verify<H: MerkleHasher>(&self, root: Bytes32, leaf_count: usize) -> bool {
+        let leaf_hash = H::hash_leaf(&self.core_transfer_state);
+        self.index < leaf_count
+            && self.proof.directions == directions(self.index, leaf_count)
+            && verify_with::<H>(root, leaf_hash, &self.proof)
+    }
+}
+
+/// Checks that the transfer id is not in the tree with the given root, built
+/// with the hasher `H`, such as `Positional`.
+///
+/// The neighbors must be ordered around the id, be included under the root
+/// and sit at adjacent indices. Their proofs must follow the directions of
+/// those indices in a tree of `leaf_count` leaves, which is what ties each
+/// neighbor to its index. Sorted pairs cannot do that, so any hasher that
+/// sorts pairs is rejected.
+pub fn verify_non_inclusion_with<H: MerkleHasher>(
+    root: Bytes32,
+    transfer_id: Bytes32,
+    proof: &NonInclusionProof,
+) -> bool {
+    if H::SORTED_PAIRS {
+        return false;
+    }
+    let leaf_count = proof.leaf_count;
+    match (&proof.left, &proof.right) {
+        (None, None) => leaf_count == 0 && root == Bytes32::default(),
+        (Some(left), None) => {
+            left.transfer_id() < transfer_id
+                && left.index + 1 == leaf_count
+                && left.verify::<H>(root, leaf_count)
+        }
+        (None, Some(right)) => {
+            transfer_id < right.transfer_id()
+                && right.index == 0
+                && right.verify::<H>(root, leaf_count)
+        }
+        (Some(left), Some(right)) => {
+            left.transfer_id() < transfer_id
+                && transfer_id < right.transfer_id()
+                && left.index + 1 == right.index
+                && left.verify::<H>(root, leaf_count)
+                && right.verify::<H>(root, leaf_count)
+        }
+    }
+}
+
+/// The side of each sibling on the path from a leaf to the root, which skips
+/// the levels where the node is promoted.
+pub(crate) fn directions(mut index: usize, leaf_count: usize) -> Vec<bool> {
+    let mut width = leaf_count;
+    let mut directions = Vec::new();
+    while width > 1 {
+        if index ^ 1 < width {
+            directions.push(index & 1 == 1);
+        }
+        index /= 2;
+        width = width.div_ceil(2);
+    }
+    directions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash;
+
+    #[test]
+    fn verify_folds_sorted_pairs() {
+        let leaf = hash::keccak(b"leaf");
+        let a = hash::keccak(b"a");
+        let b = hash::keccak(b"b");
+        let root = Keccak256::combine(&b, &Keccak256::combine(&a, &leaf));
+
+        let proof = Proof {
+            siblings: vec![a, b],
+            directions: vec![],
+        };
+        assert!(verify(root, leaf, &proof));
+
+        // Siblings out of order
+        let proof = Proof {
+            siblings: vec![b, a],
+            directions: vec![],
+        };
+        assert!(!verify(root, leaf, &proof));
+
+        // Wrong leaf
+        let proof = Proof {
+            siblings: vec![a, b],
+            directions: vec![],
+        };
+        assert!(!verify(root, a, &proof));
+    }
+
+    #[test]
+    fn verify_positional_directions() {
+        type Positional = hash::Positional;
+
+        let leaf = hash::keccak(b"leaf");
+        let a = hash::keccak(b"a");
+        let b = hash::keccak(b"b");
+        // The leaf is the right child of a left child
+        let root = Keccak256::hash_pair(&Keccak256::hash_pair(&a, &leaf), &b);
+
+        let proof = Proof {
+            siblings: vec![a, b],
+            directions: vec![true, false],
+        };
+        assert!(verify_with::<Positional>(root, leaf, &proof));
+
+        // The same siblings from the other side
+        let proof = Proof {
+            siblings: vec![a, b],
+            directions: vec![false, false],
+        };
+        assert!(!verify_with::<Positional>(root, leaf, &proof));
+
+        // Directions are required
+        let proof = Proof {
+            siblings: vec![a, b],
+            directions: vec![true],
+        };
+        assert!(!verify_with::<Positional>(root, leaf, &proof));
+    }
+
+    #[test]
+    fn verify_multiproof_shapes() {
+        let leaves: Vec<_> = (0u8..4).map(|i| hash::keccak(&[i])).collect();
+        let left = Keccak256::combine(&leaves[0], &leaves[1]);
+        let right = Keccak256::combine(&leaves[2], &leaves[3]);
+        let root = Keccak256::combine(&left, &right);
+
+        // Two leaves on opposite sides, each needing its own sibling
+        let multiproof = MultiProof {
+            leaves: vec![leaves[0], leaves[2]],
+            proof: vec![leaves[1], leaves[3]],
+            proof_flags: vec![false, false, true],
+        };
+        assert!(verify_multiproof(root, &multiproof));
+
+        // Flags that do not add up to the number of hashes
+        let multiproof = MultiProof {
+            leaves: vec![leaves[0], leaves[2]],
+            proof: vec![leaves[1], leaves[3]],
+            proof_flags: vec![false, true],
+        };
+        assert!(!verify_multiproof(root, &multiproof));
+
+        // Siblings given in the wrong order
+        let multiproof = MultiProof {
+            leaves: vec![leaves[0], leaves[2]],
+            proof: vec![leaves[3], leaves[1]],
+            proof_flags: vec![false, false, true],
+        };
+        assert!(!verify_multiproof(root, &multiproof));
+
+        // Nothing to prove
+        let multiproof = MultiProof {
+            leaves: vec![],
+            proof: vec![root],
+            proof_flags: vec![],
+        };
+        assert!(verify_multiproof(root, &multiproof));
+    }
+}
@@ -0,0 +1,426 @@
+//! A compact binary encoding of a whole `Tree`, for restoring one without
+//! re-inserting every transfer state.
+//!
+//! The layout is the magic bytes `VMT`, a version byte, the number of leaves
+//! as a big-endian u32, the channel binding as a tag byte (0 unbound, 1 bound
+//! on first insert, 2 bound to a channel) followed by the 20 byte channel
+//! address, which is zero unless bound to a channel, a byte that is 1 if the
+//! tree retains states, and the number of leaves with a state as a big-endian
+//! u32. Each leaf follows in order, as a 1 and its ABI encoded transfer state
+//! if the state was kept, or otherwise as a 0, its hash, its transfer id and
+//! the fields the tree keeps about it. Last come the root and the Keccak-256
+//! hash of everything before it.
+//!
+//! Leaf hashes and fields are derived from the state where there is one, so
+//! the root covers all of it. The checksum covers the header and the fields
+//! of leaves without a state, which the root does not.
+//!
+//! Versions before 6 stored the derived fields alongside or instead of the
+//! state, version 6 had no channel binding, version 7 did not cover the header
+//! and version 8 stored every state. None of them can be restored, and they
+//! fail with `Error::OutdatedSnapshotVersion` rather than being misread.
+
+use super::{format, format::Fields, hash, Binding, Bytes32, Error, Kept, MerkleHasher, Node, Tree};
+use std::convert::TryInto;
+
+const MAGIC: &[u8; 3] = b"VMT";
+const VERSION: u8 = 9;
+const HEADER_LEN: usize = 34;
+/// A leaf with its state.
+const STATE_LEN: usize = 1 + 384;
+/// A leaf without its state.
+const FIELDS_LEN: usize = 1 + 64 + Fields::LEN;
+/// The root and the checksum.
+const TRAILER_LEN: usize = 64;
+
+impl<H: MerkleHasher> Tree<H> {
+    /// Encodes the leaves and root of the tree.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let kept: Vec<_> = self
+            .leaves
+            .iter()
+            .map(|leaf| &self.leaf_data.kept[&leaf.transfer_id])
+            .collect();
+        let states = kept.iter().filter(|kept| kept.state.is_some()).count();
+        let mut bytes = Vec::with_capacity(
+            HEADER_LEN + states * STATE_LEN + (kept.len() - states) * FIELDS_LEN + TRAILER_LEN,
+        );
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&(self.leaves.len() as u32).to_be_bytes());
+        let (tag, channel) = match self.channel {
+            Binding::Unbound => (0, [0; 20]),
+            Binding::FirstInsert => (1, [0; 20]),
+            Binding::Channel(channel) => (2, channel),
+        };
+        bytes.push(tag);
+        bytes.extend_from_slice(&channel);
+        bytes.push(self.leaf_data.retain_states as u8);
+        bytes.extend_from_slice(&(states as u32).to_be_bytes());
+        for (leaf, kept) in self.leaves.iter().zip(kept) {
+            match &kept.state {
+                Some(state) => {
+                    bytes.push(1);
+                    bytes.extend_from_slice(&state[..]);
+                }
+                None => {
+                    bytes.push(0);
+                    bytes.extend_from_slice(&leaf.hash);
+                    bytes.extend_from_slice(&leaf.transfer_id);
+                    bytes.extend_from_slice(&kept.fields.encode());
+                }
+            }
+        }
+        bytes.extend_from_slice(&self.root());
+        let checksum = hash::keccak(&bytes);
+        bytes.extend_from_slice(&checksum);
+        bytes
+    }
+
+    /// Restores a tree from `to_bytes`. The leaves must be strictly ordered by
+    /// transfer id, belong to the channel the tree is bound to, if any, and
+    /// hash to the embedded root, and the rest must match the checksum.
+    ///
+    /// Indexes are not part of a snapshot, and have to be enabled again on the
+    /// restored tree.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < HEADER_LEN {
+            return Err(Error::WrongLength {
+                expected: HEADER_LEN,
+                got: bytes.len(),
+            });
+        }
+        if &bytes[..3] != MAGIC {
+            return Err(Error::InvalidSnapshotHeader);
+        }
+        match bytes[3] {
+            VERSION => {}
+            version @ 1..VERSION => {
+                return Err(Error::OutdatedSnapshotVersion {
+                    version,
+                    current: VERSION,
+                })
+            }
+            version => return Err(Error::UnsupportedSnapshotVersion { version }),
+        }
+        let count = u32::from_be_bytes(bytes[4..8].try_into().unwrap()) as usize;
+        let channel = &bytes[9..29];
+        let unbound = channel.iter().all(|&b| b == 0);
+        let channel = match bytes[8] {
+            0 if unbound => Binding::Unbound,
+            // Any leaf would have bound the tree to its channel
+            1 if unbound && count == 0 => Binding::FirstInsert,
+            2 => Binding::Channel(channel.try_into().unwrap()),
+            _ => return Err(Error::InvalidSnapshotHeader),
+        };
+        let retain_states = match bytes[29] {
+            0 => false,
+            1 => true,
+            _ => return Err(Error::InvalidSnapshotHeader),
+        };
+        let states = u32::from_be_bytes(bytes[30..34].try_into().unwrap()) as usize;
+        if states > count || (states > 0 && !retain_states) {
+            return Err(Error::InvalidSnapshotHeader);
+        }
+        // Saturating so that a huge count can't wrap around to the actual length
+        let expected = states
+            .saturating_mul(STATE_LEN)
+            .saturating_add((count - states).saturating_mul(FIELDS_LEN))
+            .saturating_add(HEADER_LEN + TRAILER_LEN);
+        if bytes.len() != expected {
+            return Err(Error::WrongLength {
+                expected,
+                got: bytes.len(),
+            });
+        }
+
+        let mut tree = Self {
+            channel,
+            ..Self::default()
+        };
+        tree.leaf_data.retain_states = retain_states;
+        tree.leaves.reserve_exact(count);
+        // Each leaf is checked against the kinds the header has left, so the
+        // records end exactly at the trailer
+        let mut left = [count - states, states];
+        let mut rest = &bytes[HEADER_LEN..expected - TRAILER_LEN];
+        for index in 0..count {
+            let kind = rest[0] as usize;
+            match left.get_mut(kind) {
+                Some(n) if *n > 0 => *n -= 1,
+                _ => return Err(Error::InvalidSnapshotLeaf { index }),
+            }
+            let (node, kept) = if kind == 1 {
+                let state: &[u8; 384] = rest[1..STATE_LEN].try_into().unwrap();
+                rest = &rest[STATE_LEN..];
+                tree.channel.check(state)?;
+                (format::encoded_to_node::<H>(state), tree.leaf_data.keep(state))
+            } else {
+                let node = Node {
+                    hash: rest[1..33].try_into().unwrap(),
+                    transfer_id: rest[33..65].try_into().unwrap(),
+                };
+                let fields = Fields::decode(rest[65..FIELDS_LEN].try_into().unwrap());
+                rest = &rest[FIELDS_LEN..];
+                tree.channel.check_channel(fields.channel_address())?;
+                (node, Kept { fields, state: None })
+            };
+            if let Some(previous) = tree.leaves.last() {
+                if previous.transfer_id >= node.transfer_id {
+                    return Err(Error::UnsortedLeaves { index });
+                }
+            }
+            tree.leaf_data.on_added(&node, kept);
+            tree.leaves.push(node);
+        }
+
+        let (body, checksum) = bytes.split_at(expected - 32);
+        let expected: Bytes32 = body[body.len() - 32..].try_into().unwrap();
+        let computed = tree.root();
+        if computed != expected {
+            return Err(Error::RootMismatch { expected, computed });
+        }
+        if checksum != hash::keccak(body) {
+            return Err(Error::ChecksumMismatch);
+        }
+        Ok(tree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{encoded_transfer, transfer, tree};
+    use crate::Keccak256;
+
+    /// Like `tree`, retaining the states.
+    fn retaining(size: usize) -> Tree {
+        let mut tree = Tree::new();
+        tree.retain_states();
+        for i in 0..size {
+            tree.insert_hex(&encoded_transfer(i)).unwrap();
+        }
+        tree
+    }
+
+    fn check_round_trip(tree: &Tree) -> Vec<u8> {
+        let bytes = tree.to_bytes();
+        let restored: Tree = Tree::from_bytes(&bytes).unwrap();
+        assert_eq!(restored.leaves, tree.leaves);
+        assert_eq!(restored.locked_balances(), tree.locked_balances());
+        assert_eq!(restored.leaf_data.kept, tree.leaf_data.kept);
+        assert_eq!(restored.retains_states(), tree.retains_states());
+        assert_eq!(restored.root(), tree.root());
+        bytes
+    }
+
+    #[test]
+    fn round_trip() {
+        for size in 0..10 {
+            let bytes = check_round_trip(&tree(size));
+            assert_eq!(bytes.len(), HEADER_LEN + size * FIELDS_LEN + TRAILER_LEN);
+            let bytes = check_round_trip(&retaining(size));
+            assert_eq!(bytes.len(), HEADER_LEN + size * STATE_LEN + TRAILER_LEN);
+        }
+
+        // Only the leaves inserted while retaining have their states
+        let mut tree = tree(3);
+        tree.retain_states();
+        for i in 3..6 {
+            tree.insert_hex(&encoded_transfer(i)).unwrap();
+        }
+        let bytes = check_round_trip(&tree);
+        assert_eq!(
+            bytes.len(),
+            HEADER_LEN + 3 * FIELDS_LEN + 3 * STATE_LEN + TRAILER_LEN
+        );
+        tree.discard_states();
+        check_round_trip(&tree);
+    }
+
+    #[test]
+    fn keeps_channel_binding() {
+        let state = transfer(0);
+        let channel = state.channel_address;
+        let mut other = transfer(1);
+        other.channel_address[19] ^= 1;
+
+        let mut tree: Tree = Tree::with_channel(channel);
+        tree.insert_state(&state).unwrap();
+        let mut restored: Tree = Tree::from_bytes(&tree.to_bytes()).unwrap();
+        assert_eq!(restored.channel(), Some(channel));
+        assert_eq!(
+            restored.insert_state(&other),
+            Err(Error::ChannelMismatch {
+                expected: channel,
+                got: other.channel_address
+            })
+        );
+
+        // Still waiting for its first insert to pick the channel
+        let tree: Tree = Tree::bound_on_first_insert();
+        let mut restored: Tree = Tree::from_bytes(&tree.to_bytes()).unwrap();
+        restored.insert_state(&other).unwrap();
+        assert_eq!(
+            restored.insert_state(&state),
+            Err(Error::ChannelMismatch {
+                expected: other.channel_address,
+                got: channel
+            })
+        );
+
+        let mut restored: Tree = Tree::from_bytes(&Tree::new().to_bytes()).unwrap();
+        restored.insert_state(&state).unwrap();
+        restored.insert_state(&other).unwrap();
+    }
+
+    #[test]
+    fn rejects_corruption() {
+        let bytes = retaining(3).to_bytes();
+
+        assert_eq!(
+            Tree::<Keccak256>::from_bytes(&bytes[..bytes.len() - 1]).map(|_| ()),
+            Err(Error::WrongLength {
+                expected: bytes.len(),
+                got: bytes.len() - 1
+            })
+        );
+
+        let mut bad = bytes.clone();
+        bad[0] = b'X';
+        assert_eq!(
+            Tree::<Keccak256>::from_bytes(&bad).map(|_| ()),
+            Err(Error::InvalidSnapshotHeader)
+        );
+
+        let mut bad = bytes.clone();
+        for version in 1..VERSION {
+            bad[3] = version;
+            assert_eq!(
+                Tree::<Keccak256>::from_bytes(&bad).map(|_| ()),
+                Err(Error::OutdatedSnapshotVersion {
+                    version,
+                    current: VERSION
+                })
+            );
+        }
+        for version in [0, VERSION + 1] {
+            bad[3] = version;
+            assert_eq!(
+                Tree::<Keccak256>::from_bytes(&bad).map(|_| ()),
+                Err(Error::UnsupportedSnapshotVersion { version })
+            );
+        }
+
+        // An unknown binding, a channel address without a channel binding,
+        // leaves in a tree still waiting for its first insert, an unknown
+        // flag for retaining states, states in a tree that does not retain
+        // them, and more states than leaves
+        for (at, value) in [(8, 3), (9, 1), (8, 1), (29, 2), (29, 0), (33, 4)] {
+            let mut bad = bytes.clone();
+            bad[at] = value;
+            assert_eq!(
+                Tree::<Keccak256>::from_bytes(&bad).map(|_| ()),
+                Err(Error::InvalidSnapshotHeader)
+            );
+        }
+
+        // A header that is well formed but changed
+        let empty = Tree::<Keccak256>::new().to_bytes();
+        let mut bad = empty.clone();
+        bad[8] = 1;
+        assert_eq!(
+            Tree::<Keccak256>::from_bytes(&bad).map(|_| ()),
+            Err(Error::ChecksumMismatch)
+        );
+        let mut bad = empty;
+        *bad.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            Tree::<Keccak256>::from_bytes(&bad).map(|_| ()),
+            Err(Error::ChecksumMismatch)
+        );
+
+        // Leaves from another channel than the one bound, whether or not
+        // their states were kept
+        for retain in [false, true] {
+            let state = transfer(0);
+            let mut bound: Tree = Tree::with_channel(state.channel_address);
+            if retain {
+                bound.retain_states();
+            }
+            bound.insert_state(&state).unwrap();
+            let mut bad = bound.to_bytes();
+            bad[28] ^= 1;
+            let mut expected = state.channel_address;
+            expected[19] ^= 1;
+            assert_eq!(
+                Tree::<Keccak256>::from_bytes(&bad).map(|_| ()),
+                Err(Error::ChannelMismatch {
+                    expected,
+                    got: state.channel_address
+                })
+            );
+        }
+
+        // Swapping the first two leaves breaks the ordering
+        let mut bad = bytes.clone();
+        let (first, second) = bad[HEADER_LEN..HEADER_LEN + 2 * STATE_LEN].split_at_mut(STATE_LEN);
+        first.swap_with_slice(second);
+        assert_eq!(
+            Tree::<Keccak256>::from_bytes(&bad).map(|_| ()),
+            Err(Error::UnsortedLeaves { index: 1 })
+        );
+
+        // As does repeating a leaf
+        let mut bad = bytes.clone();
+        bad.copy_within(HEADER_LEN..HEADER_LEN + STATE_LEN, HEADER_LEN + STATE_LEN);
+        assert_eq!(
+            Tree::<Keccak256>::from_bytes(&bad).map(|_| ()),
+            Err(Error::UnsortedLeaves { index: 1 })
+        );
+
+        // Changing any part of a state, such as the transfer timeout or the
+        // balance, no longer matches the root
+        for offset in [10 * 32 + 31, 6 * 32 + 31, 3 * 32 + 31] {
+            let mut bad = bytes.clone();
+            bad[HEADER_LEN + 1 + offset] ^= 1;
+            assert!(matches!(
+                Tree::<Keccak256>::from_bytes(&bad),
+                Err(Error::RootMismatch { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_corrupt_fields() {
+        let bytes = tree(3).to_bytes();
+
+        // A leaf of a kind the header has none of, or of no kind at all
+        for kind in [1, 2] {
+            let mut bad = bytes.clone();
+            bad[HEADER_LEN + FIELDS_LEN] = kind;
+            assert_eq!(
+                Tree::<Keccak256>::from_bytes(&bad).map(|_| ()),
+                Err(Error::InvalidSnapshotLeaf { index: 1 })
+            );
+        }
+
+        // The root covers the leaf hashes
+        let mut bad = bytes.clone();
+        bad[HEADER_LEN + 1] ^= 1;
+        assert!(matches!(
+            Tree::<Keccak256>::from_bytes(&bad),
+            Err(Error::RootMismatch { .. })
+        ));
+
+        // And the checksum covers the fields, such as the amounts
+        for offset in [0, 100, Fields::LEN - 1] {
+            let mut bad = bytes.clone();
+            bad[HEADER_LEN + 65 + offset] ^= 1;
+            assert_eq!(
+                Tree::<Keccak256>::from_bytes(&bad).map(|_| ()),
+                Err(Error::ChecksumMismatch)
+            );
+        }
+    }
+}
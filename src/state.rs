@@ -0,0 +1,131 @@
+use super::{format, Bytes32, Error};
+use ethnum::U256;
+use std::convert::TryInto;
+
+pub type Address = [u8; 20];
+
+/// The state of a Vector transfer that is committed to by a leaf.
+///
+/// Fields are listed in the order of the `CoreTransferState` struct in the
+/// Vector contracts. Every field is static, so the ABI encoding is one 32 byte
+/// word for each value, with the balance inlined.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoreTransferState {
+    pub channel_address: Address,
+    pub transfer_id: Bytes32,
+    pub transfer_definition: Address,
+    pub initiator: Address,
+    pub responder: Address,
+    pub asset_id: Address,
+    pub balance: Balance,
+    pub transfer_timeout: U256,
+    pub initial_state_hash: Bytes32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Balance {
+    pub amount: [U256; 2],
+    pub to: [Address; 2],
+}
+
+/// Decodes a left padded address from an ABI word.
+fn address_word(word: &Bytes32, field: &'static str) -> Result<Address, Error> {
+    if word[..12].iter().any(|&b| b != 0) {
+        return Err(Error::InvalidAddress { field });
+    }
+    Ok(word[12..].try_into().unwrap())
+}
+
+impl CoreTransferState {
+    /// Decodes the ABI encoding of a transfer state.
+    ///
+    /// Addresses must be left padded with zeros, and the error names the first
+    /// field that is not.
+    pub fn decode(encoded: &[u8; 384]) -> Result<Self, Error> {
+        let word = |i: usize| -> Bytes32 { encoded[i * 32..(i + 1) * 32].try_into().unwrap() };
+        let address = |i: usize, field: &'static str| address_word(&word(i), field);
+        let uint = |i: usize| U256::from_be_bytes(word(i));
+
+        Ok(Self {
+            channel_address: address(0, "channelAddress")?,
+            transfer_id: word(1),
+            transfer_definition: address(2, "transferDefinition")?,
+            initiator: address(3, "initiator")?,
+            responder: address(4, "responder")?,
+            asset_id: address(5, "assetId")?,
+            balance: Balance {
+                amount: [uint(6), uint(7)],
+                to: [address(8, "balance.to[0]")?, address(9, "balance.to[1]")?],
+            },
+            transfer_timeout: uint(10),
+            initial_state_hash: word(11),
+        })
+    }
+
+    /// Decodes just the channel address of an ABI encoded transfer state.
+    pub(crate) fn decode_channel(encoded: &[u8; 384]) -> Result<Address, Error> {
+        address_word(encoded[..32].try_into().unwrap(), "channelAddress")
+    }
+
+    /// Decodes a 0x-hex ABI encoded transfer state.
+    pub fn from_hex(core_transfer_state: &str) -> Result<Self, Error> {
+        Self::decode(&format::hex_to_encoded(core_transfer_state)?)
+    }
+
+    /// Produces the ABI encoding, which is what a leaf hashes.
+    pub fn encode(&self) -> [u8; 384] {
+        let mut encoded = [0u8; 384];
+        let mut words = encoded.chunks_exact_mut(32);
+        let mut put = |value: &[u8]| {
+            let word = words.next().unwrap();
+            word[32 - value.len()..].copy_from_slice(value);
+        };
+
+        put(&self.channel_address);
+        put(&self.transfer_id);
+        put(&self.transfer_definition);
+        put(&self.initiator);
+        put(&self.responder);
+        put(&self.asset_id);
+        put(&self.balance.amount[0].to_be_bytes());
+        put(&self.balance.amount[1].to_be_bytes());
+        put(&self.balance.to[0]);
+        put(&self.balance.to[1]);
+        put(&self.transfer_timeout.to_be_bytes());
+        put(&self.initial_state_hash);
+        encoded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::encoded_transfer;
+
+    #[test]
+    fn round_trip() {
+        let encoded = format::hex_to_encoded(&encoded_transfer(0)).unwrap();
+        let state = CoreTransferState::decode(&encoded).unwrap();
+
+        let mut channel_address = [0u8; 20];
+        channel_address[0] = 0xcc;
+        channel_address[1] = 0xc0;
+        assert_eq!(state.channel_address, channel_address);
+        assert_eq!(&state.transfer_id[..], &encoded[32..64]);
+        assert_eq!(state.balance.amount, [U256::ONE, U256::ZERO]);
+        assert_eq!(state.transfer_timeout, U256::ONE);
+        assert_eq!(state.initial_state_hash[..3], [0xab, 0xcd, 0xef]);
+
+        assert_eq!(&state.encode()[..], &encoded[..]);
+    }
+
+    #[test]
+    fn invalid_address() {
+        let mut encoded = format::hex_to_encoded(&encoded_transfer(0)).unwrap();
+        encoded[32 * 4] = 1;
+        assert_eq!(
+            CoreTransferState::decode(&encoded),
+            Err(Error::InvalidAddress { field: "responder" })
+        );
+    }
+}
@@ -1,6 +1,10 @@
+use super::{Address, CoreTransferState, Tree, U256};
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
 
+pub(crate) const ALICE: Address = [0xa; 20];
+pub(crate) const BOB: Address = [0xb; 20];
+
 pub(crate) fn rand<T>(seed: T) -> u64
 where
     T: Hash,
@@ -9,3 +13,70 @@ where
     seed.hash(&mut hasher);
     hasher.finish()
 }
+
+/// Produces an encoded core transfer state with a transfer id derived from the seed.
+pub(crate) fn encoded_transfer<T>(seed: T) -> String
+where
+    T: Hash,
+{
+    // Start with a transfer that was generated by tests
+    let mut data = "0x000000000000000000000000ccc00000000000000000000000000000000000000de5846e2e915d4bad7b8fbb3822b932367a24691960d57cd983257037553411000000000000000000000000def0000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000010000000000000000000000000000000000000000000000000000000000000000000000000000000000000000aa00000000000000000000000000000000000000000000000000000000000000bbb00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001abcdef0000000000000000000000000000000000000000000000000000000000".to_owned();
+    let seed = rand(seed);
+    for c in 66..130 {
+        let h = (rand((seed, c)) % 16) as usize;
+        // Safe because the replacement is a single ascii hex digit
+        unsafe { data.as_bytes_mut()[c] = b"0123456789abcdef"[h] };
+    }
+    data
+}
+
+/// Decodes `encoded_transfer`, for tests that change some fields first.
+pub(crate) fn transfer<T>(seed: T) -> CoreTransferState
+where
+    T: Hash,
+{
+    CoreTransferState::from_hex(&encoded_transfer(seed)).unwrap()
+}
+
+/// A transfer state in one of a few channels.
+pub(crate) fn transfer_in_channel(channel: u8, seed: u32) -> CoreTransferState {
+    let mut state = transfer(seed);
+    state.channel_address[19] = channel;
+    state
+}
+
+/// A transfer of one of a few assets between `ALICE` and `BOB`.
+pub(crate) fn transfer_between(seed: u64) -> CoreTransferState {
+    let mut state = transfer(seed);
+    state.asset_id[19] = (seed % 3) as u8;
+    state.balance.amount = [U256::from(rand((seed, 0)) % 1000), U256::ZERO];
+    if seed.is_multiple_of(4) {
+        state.balance.amount[1] = U256::from(rand((seed, 1)) % 1000);
+    }
+    if seed.is_multiple_of(2) {
+        state.initiator = ALICE;
+        state.responder = BOB;
+    } else {
+        state.initiator = BOB;
+        state.responder = ALICE;
+    }
+    state
+}
+
+/// A transfer between a few participants with a few definitions.
+pub(crate) fn transfer_with_addresses(seed: u8) -> CoreTransferState {
+    let mut state = transfer(seed);
+    state.initiator = [seed % 3; 20];
+    state.responder = [3 + seed % 2; 20];
+    state.transfer_definition = [10 + seed % 4; 20];
+    state
+}
+
+/// A tree with the transfers of the seeds up to the size.
+pub(crate) fn tree(size: usize) -> Tree {
+    let mut tree = Tree::new();
+    for i in 0..size {
+        tree.insert_hex(&encoded_transfer(i)).unwrap();
+    }
+    tree
+}
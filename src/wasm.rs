@@ -0,0 +1,360 @@
+// wasm-bindgen passes JS arrays as boxed slices
+#![allow(clippy::boxed_local)]
+
+use super::format::{self, fmt_address, hex_encode};
+use super::{verify, Bytes32, Error, Forest, Proof, Tree};
+use wasm_bindgen::prelude::*;
+
+/// The Keccak-256 `Tree`, which is exported to JS under that name.
+#[wasm_bindgen(js_name = Tree)]
+#[derive(Debug, Clone, Default)]
+pub struct JsTree(Tree);
+
+#[wasm_bindgen(js_class = Tree)]
+impl JsTree {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self(Tree::new())
+    }
+
+    /// Creates a tree that only accepts transfers in the 0x-hex channel.
+    #[wasm_bindgen(js_name = forChannel)]
+    pub fn for_channel_js(channel: &str) -> Result<JsTree, JsValue> {
+        Ok(Self(Tree::with_channel(format::hex_to_address(channel)?)))
+    }
+
+    /// The channel the tree is bound to as a 0x-hex string, or undefined.
+    #[wasm_bindgen(getter)]
+    pub fn channel(&self) -> Option<String> {
+        self.0.channel().map(fmt_address)
+    }
+
+    #[wasm_bindgen(js_name = insertHex)]
+    pub fn insert_hex_js(&mut self, core_transfer_state: &str) -> Result<(), JsValue> {
+        Ok(self.0.insert_hex(core_transfer_state)?)
+    }
+
+    #[wasm_bindgen(js_name = insertBytes)]
+    pub fn insert_bytes_js(&mut self, core_transfer_state: &[u8]) -> Result<(), JsValue> {
+        Ok(self.0.insert_bytes(core_transfer_state)?)
+    }
+
+    /// Inserts the transfer, replacing any with the same id, and returns the
+    /// replaced leaf hash as a 0x-hex string or undefined.
+    #[wasm_bindgen(js_name = upsertHex)]
+    pub fn upsert_hex_js(&mut self, core_transfer_state: &str) -> Result<Option<String>, JsValue> {
+        let previous = self.0.upsert_hex(core_transfer_state)?;
+        Ok(previous.map(prefixed_hex))
+    }
+
+    /// Inserts every transfer state of an array of 0x-hex strings, or none of
+    /// them if any fail.
+    #[wasm_bindgen(js_name = insertManyHex)]
+    pub fn insert_many_hex_js(
+        &mut self,
+        core_transfer_states: Box<[JsValue]>,
+    ) -> Result<(), JsValue> {
+        let mut states = Vec::with_capacity(core_transfer_states.len());
+        let mut errors = Vec::new();
+        for (position, state) in core_transfer_states.iter().enumerate() {
+            match state.as_string() {
+                Some(state) => states.push(state),
+                None => errors.push((position, Error::NotString { index: position })),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(Error::Batch { errors }.into());
+        }
+        Ok(self.0.insert_many_hex(states)?)
+    }
+
+    /// Inserts every transfer state of an array of `Uint8Array`s, or none of
+    /// them if any fail.
+    #[wasm_bindgen(js_name = insertManyBytes)]
+    pub fn insert_many_bytes_js(
+        &mut self,
+        core_transfer_states: Box<[JsValue]>,
+    ) -> Result<(), JsValue> {
+        let mut states = Vec::with_capacity(core_transfer_states.len());
+        let mut errors = Vec::new();
+        for (position, state) in core_transfer_states.iter().enumerate() {
+            match state.dyn_ref::<js_sys::Uint8Array>() {
+                Some(state) => states.push(state.to_vec()),
+                None => errors.push((position, Error::NotBytes)),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(Error::Batch { errors }.into());
+        }
+        Ok(self.0.insert_many_bytes(states)?)
+    }
+
+    /// Deletes every transfer of an array of 0x-hex ids, or none of them if
+    /// any are malformed. Returns a `Map` from the 0x-hex id of each removed
+    /// leaf to its hash, in transfer id order.
+    #[wasm_bindgen(js_name = deleteMany)]
+    pub fn delete_many_js(
+        &mut self,
+        transfer_ids: Box<[JsValue]>,
+    ) -> Result<js_sys::Map, JsValue> {
+        let mut ids = Vec::with_capacity(transfer_ids.len());
+        let mut errors = Vec::new();
+        for (position, id) in transfer_ids.iter().enumerate() {
+            let id = id
+                .as_string()
+                .ok_or(Error::NotString { index: position })
+                .and_then(|id| format::hex_to_bytes32(&id));
+            match id {
+                Ok(id) => ids.push(id),
+                Err(error) => errors.push((position, error)),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(Error::Batch { errors }.into());
+        }
+
+        let removed = js_sys::Map::new();
+        for (transfer_id, hash) in self.0.delete_many(ids) {
+            removed.set(
+                &JsValue::from_str(&prefixed_hex(transfer_id)),
+                &JsValue::from_str(&prefixed_hex(hash)),
+            );
+        }
+        Ok(removed)
+    }
+
+    /// Deletes the transfer and returns its leaf hash as a 0x-hex string, or
+    /// undefined if it was not in the tree.
+    #[wasm_bindgen(js_name = deleteId)]
+    pub fn delete_id_js(&mut self, transfer_id: &str) -> Result<Option<String>, JsValue> {
+        Ok(self.0.delete_hex(transfer_id)?.map(prefixed_hex))
+    }
+
+    #[wasm_bindgen(js_name = root)]
+    pub fn root_js(&self) -> JsValue {
+        let root = self.0.root();
+        JsValue::from_str(&prefixed_hex(root))
+    }
+
+    /// The number of transfers in the tree.
+    #[wasm_bindgen(getter)]
+    pub fn size(&self) -> usize {
+        self.0.len()
+    }
+
+    #[wasm_bindgen(js_name = has)]
+    pub fn has_js(&self, transfer_id: &str) -> Result<bool, JsValue> {
+        let transfer_id = format::hex_to_bytes32(transfer_id)?;
+
+        Ok(self.0.contains(transfer_id))
+    }
+
+    /// Returns the id of every transfer as 0x-hex strings, in tree order.
+    #[wasm_bindgen(js_name = transferIds)]
+    pub fn transfer_ids_js(&self) -> Box<[JsValue]> {
+        prefixed_ids(self.0.iter().map(|(transfer_id, _)| transfer_id))
+    }
+
+    /// Returns the ids of the transfers with a timeout before the timestamp,
+    /// given as a decimal string, as 0x-hex strings soonest first.
+    #[wasm_bindgen(js_name = expiringBefore)]
+    pub fn expiring_before_js(&self, timestamp: &str) -> Result<Box<[JsValue]>, JsValue> {
+        let timestamp = format::decimal_to_u256(timestamp)?;
+
+        Ok(prefixed_ids(
+            self.0
+                .expiring_before(timestamp)
+                .map(|(transfer_id, _)| transfer_id),
+        ))
+    }
+
+    /// Returns a `Map` from the address of every asset with an amount locked
+    /// in transfers to that amount as a decimal string.
+    #[wasm_bindgen(js_name = lockedBalances)]
+    pub fn locked_balances_js(&self) -> js_sys::Map {
+        let balances = js_sys::Map::new();
+        for (asset_id, amount) in self.0.locked_balances() {
+            balances.set(
+                &JsValue::from_str(&fmt_address(asset_id)),
+                &JsValue::from_str(&amount.to_string()),
+            );
+        }
+        balances
+    }
+
+    /// Keeps indexes of the transfers by initiator, responder and transfer
+    /// definition, so that looking them up does not scan every transfer.
+    #[wasm_bindgen(js_name = enableIndexes)]
+    pub fn enable_indexes_js(&mut self) {
+        self.0.enable_indexes()
+    }
+
+    /// Returns the ids of the transfers with the 0x-hex initiator as 0x-hex
+    /// strings, in tree order.
+    #[wasm_bindgen(js_name = transfersByInitiator)]
+    pub fn transfers_by_initiator_js(&self, initiator: &str) -> Result<Box<[JsValue]>, JsValue> {
+        let initiator = format::hex_to_address(initiator)?;
+        Ok(prefixed_ids(self.0.transfers_by_initiator(initiator)))
+    }
+
+    /// Like `transfersByInitiator`, for the responder.
+    #[wasm_bindgen(js_name = transfersByResponder)]
+    pub fn transfers_by_responder_js(&self, responder: &str) -> Result<Box<[JsValue]>, JsValue> {
+        let responder = format::hex_to_address(responder)?;
+        Ok(prefixed_ids(self.0.transfers_by_responder(responder)))
+    }
+
+    /// Like `transfersByInitiator`, for the transfer definition.
+    #[wasm_bindgen(js_name = transfersByDefinition)]
+    pub fn transfers_by_definition_js(
+        &self,
+        transfer_definition: &str,
+    ) -> Result<Box<[JsValue]>, JsValue> {
+        let transfer_definition = format::hex_to_address(transfer_definition)?;
+        Ok(prefixed_ids(
+            self.0.transfers_by_definition(transfer_definition),
+        ))
+    }
+
+    #[wasm_bindgen(js_name = serialize)]
+    pub fn serialize_js(&self) -> Vec<u8> {
+        self.0.to_bytes()
+    }
+
+    #[wasm_bindgen(js_name = deserialize)]
+    pub fn deserialize_js(bytes: &[u8]) -> Result<JsTree, JsValue> {
+        Ok(Self(Tree::from_bytes(bytes)?))
+    }
+
+    /// Returns the sibling hashes for the transfer as 0x-hex strings, or
+    /// undefined if the transfer is not in the tree.
+    #[wasm_bindgen(js_name = proof)]
+    pub fn proof_js(&self, transfer_id: &str) -> Result<Option<Box<[JsValue]>>, JsValue> {
+        let transfer_id = format::hex_to_bytes32(transfer_id)?;
+
+        Ok(self.0.proof(transfer_id).map(|proof| {
+            proof
+                .siblings
+                .iter()
+                .map(|sibling| JsValue::from_str(&prefixed_hex(*sibling)))
+                .collect()
+        }))
+    }
+}
+
+/// The Keccak-256 `Forest`, which is exported to JS under that name. Channel
+/// addresses, transfer ids and hashes are all 0x-hex strings.
+#[wasm_bindgen(js_name = Forest)]
+#[derive(Debug, Clone, Default)]
+pub struct JsForest(Forest);
+
+#[wasm_bindgen(js_class = Forest)]
+impl JsForest {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self(Forest::new())
+    }
+
+    #[wasm_bindgen(js_name = insertHex)]
+    pub fn insert_hex_js(&mut self, core_transfer_state: &str) -> Result<(), JsValue> {
+        Ok(self.0.insert_hex(core_transfer_state)?)
+    }
+
+    #[wasm_bindgen(js_name = insertBytes)]
+    pub fn insert_bytes_js(&mut self, core_transfer_state: &[u8]) -> Result<(), JsValue> {
+        Ok(self.0.insert_bytes(core_transfer_state)?)
+    }
+
+    /// Deletes the transfer from the channel and returns its leaf hash, or
+    /// undefined if it was not there.
+    #[wasm_bindgen(js_name = deleteId)]
+    pub fn delete_id_js(
+        &mut self,
+        channel: &str,
+        transfer_id: &str,
+    ) -> Result<Option<String>, JsValue> {
+        let channel = format::hex_to_address(channel)?;
+        let transfer_id = format::hex_to_bytes32(transfer_id)?;
+
+        Ok(self.0.delete_id(channel, transfer_id).map(prefixed_hex))
+    }
+
+    #[wasm_bindgen(js_name = root)]
+    pub fn root_js(&self, channel: &str) -> Result<String, JsValue> {
+        let channel = format::hex_to_address(channel)?;
+
+        Ok(prefixed_hex(self.0.root(channel)))
+    }
+
+    /// Returns the sibling hashes for the transfer in the channel, or
+    /// undefined if it is not there.
+    #[wasm_bindgen(js_name = proof)]
+    pub fn proof_js(
+        &self,
+        channel: &str,
+        transfer_id: &str,
+    ) -> Result<Option<Box<[JsValue]>>, JsValue> {
+        let channel = format::hex_to_address(channel)?;
+        let transfer_id = format::hex_to_bytes32(transfer_id)?;
+
+        Ok(self.0.proof(channel, transfer_id).map(|proof| {
+            proof
+                .siblings
+                .iter()
+                .map(|sibling| JsValue::from_str(&prefixed_hex(*sibling)))
+                .collect()
+        }))
+    }
+
+    /// Returns a `Map` from the address of every channel with transfers to
+    /// its root.
+    #[wasm_bindgen(js_name = roots)]
+    pub fn roots_js(&self) -> js_sys::Map {
+        let roots = js_sys::Map::new();
+        for (channel, root) in self.0.roots() {
+            roots.set(
+                &JsValue::from_str(&fmt_address(channel)),
+                &JsValue::from_str(&prefixed_hex(root)),
+            );
+        }
+        roots
+    }
+}
+
+/// Checks a proof produced by `Tree.proof` against a root. All arguments are
+/// 0x-hex strings.
+#[wasm_bindgen(js_name = verifyProof)]
+pub fn verify_proof_js(
+    root: &str,
+    leaf_hash: &str,
+    proof: Box<[JsValue]>,
+) -> Result<bool, JsValue> {
+    let root = format::hex_to_bytes32(root)?;
+    let leaf_hash = format::hex_to_bytes32(leaf_hash)?;
+    let siblings = proof
+        .iter()
+        .enumerate()
+        .map(|(index, sibling)| {
+            let sibling = sibling.as_string().ok_or(Error::NotString { index })?;
+            format::hex_to_bytes32(&sibling)
+        })
+        .collect::<Result<_, _>>()?;
+
+    // `verify` hashes with Keccak256, which sorts each pair, so it ignores
+    // directions
+    let proof = Proof {
+        siblings,
+        directions: Vec::new(),
+    };
+    Ok(verify(root, leaf_hash, &proof))
+}
+
+fn prefixed_hex(value: Bytes32) -> String {
+    "0x".to_owned() + &hex_encode(value)
+}
+
+fn prefixed_ids(transfer_ids: impl Iterator<Item = Bytes32>) -> Box<[JsValue]> {
+    transfer_ids
+        .map(|transfer_id| JsValue::from_str(&prefixed_hex(transfer_id)))
+        .collect()
+}